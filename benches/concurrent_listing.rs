@@ -0,0 +1,98 @@
+//! Measures how long a small directory listing takes while other clients keep listing a huge directory
+//!
+//! Filesystem work runs on the blocking thread pool, so the small listing's latency should stay about
+//! flat as the load goes up, instead of growing once the heavy listings have taken every runtime thread.
+//! It fails if the small listing's median gets as slow as a heavy listing on its own, which is what waiting behind one looks like.
+//! Run with `cargo bench`
+
+use std::{fs, io::{Read, Write}, net::{TcpListener, TcpStream}, path::Path, process::{Command, Stdio}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
+
+const HUGE_DIRECTORY_ENTRIES: usize = 50_000;
+const SMALL_DIRECTORY_ENTRIES: usize = 20;
+/// Sorting by modification time has every entry stat'd
+const HUGE_LISTING: &str = "/huge/?data=true&sort=modified&limit=1";
+const SAMPLES: usize = 200;
+// kept under the default --fs-jobs, past that the small listings (rightly) wait their turn
+const LOADS: [usize; 4] = [0, 4, 16, 32];
+const HUGE_LISTING_SAMPLES: usize = 5;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Makes a request and returns how long it took to read the whole response
+fn get(port: u16, path: &str) -> Duration {
+    let start_time = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"), "{path} didn't return 200");
+    start_time.elapsed()
+}
+
+fn create_files(directory: &Path, count: usize) {
+    fs::create_dir_all(directory).unwrap();
+    for i in 0..count {
+        fs::write(directory.join(format!("file {i}.txt")), i.to_string()).unwrap();
+    }
+}
+
+fn main() {
+    let root = std::env::temp_dir().join(format!("gshare3-bench-{}", std::process::id()));
+    create_files(&root.join("small"), SMALL_DIRECTORY_ENTRIES);
+    create_files(&root.join("huge"), HUGE_DIRECTORY_ENTRIES);
+
+    // asks the OS for a free port, which is very likely still free when the server starts a moment later
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_gshare3"))
+        .arg(&root)
+        .args(["--private", "--no-port-increment", "--no-dir-sizes", "--no-thumbnails", "--no-content-search", "-p", &port.to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start_time = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(start_time.elapsed() < STARTUP_TIMEOUT, "The server didn't start listening within {STARTUP_TIMEOUT:?}");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    println!("small listing latency with concurrent {HUGE_DIRECTORY_ENTRIES}-entry listings");
+    // a small listing that had to wait for a heavy one to finish would take at least this long, however many cores there are to share
+    let mut huge_latencies = (0..HUGE_LISTING_SAMPLES).map(|_| get(port, HUGE_LISTING)).collect::<Vec<_>>();
+    huge_latencies.sort_unstable();
+    let huge_latency = huge_latencies[HUGE_LISTING_SAMPLES / 2];
+    println!("one {HUGE_DIRECTORY_ENTRIES}-entry listing on its own takes {:.2}ms", huge_latency.as_secs_f64() * 1000.0);
+
+    println!("{:>5} {:>10} {:>10} {:>10}", "load", "p50", "p90", "p99");
+    let mut slow_loads = Vec::new();
+    for load in LOADS {
+        let stop = Arc::new(AtomicBool::new(false));
+        let load_threads = (0..load).map(|_| {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    get(port, HUGE_LISTING);
+                }
+            })
+        }).collect::<Vec<_>>();
+        // lets the heavy listings get going first
+        thread::sleep(Duration::from_millis(500));
+
+        let mut latencies = (0..SAMPLES).map(|_| get(port, "/small/?data=true")).collect::<Vec<_>>();
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[(SAMPLES * p / 100).min(SAMPLES - 1)].as_secs_f64() * 1000.0;
+        println!("{load:>5} {:>8.2}ms {:>8.2}ms {:>8.2}ms", percentile(50), percentile(90), percentile(99));
+        if latencies[SAMPLES / 2] >= huge_latency {
+            slow_loads.push(load);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for load_thread in load_threads {
+            load_thread.join().unwrap();
+        }
+    }
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(slow_loads.is_empty(), "Small listings took as long as a whole heavy listing under loads {slow_loads:?}, so they're waiting behind them");
+}
@@ -0,0 +1,466 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+use axum::{extract::{multipart::MultipartRejection, rejection::QueryRejection, ConnectInfo, Multipart, Query, State}, http::{StatusCode, Uri}, response::{IntoResponse, Response}, routing::{any, get, post, MethodRouter}, Json, Router};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use utoipa::{openapi::Server, IntoParams, OpenApi, ToSchema};
+use crate::{absolute_url, archive, content_index::GrepResults, operations, decode_path_components, deserialize_flag, figure_out_request_path, file_metadata::FileMetadata, filesystem_permit, get_archive_entries, get_directory_entries, get_search_roots, ip_authorized, listing::{ListingOptions, SortKey, SortOrder}, ndjson_response, receive_uploads, run_blocking, search::{self, SearchMode}, tree::{self, ManifestEntry, TreeEntry}, url_encoding, AppState, EntryData, FiguredOutRequestPath};
+
+/// Everything under here is the api rather than served files
+const PREFIX: &str = "/api/v1";
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(openapi, list, stat, tree, manifest, search_names, grep, upload),
+    components(schemas(ApiError, ErrorKind, SortKey, SortOrder, SearchMode)),
+    info(description = "Browse, search and upload to a gshare3 server. Every path can be empty for the root, and can contain slashes"),
+)]
+struct ApiDoc;
+
+/// What went wrong, so clients can tell errors apart without going by the message
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The client's IP isn't whitelisted
+    NotWhitelisted,
+    NotFound,
+    /// A query parameter is missing or invalid, like an unknown hash algorithm or a broken glob
+    InvalidQuery,
+    /// Only directories (and the virtual directory) can be listed, searched or uploaded to
+    NotADirectory,
+    /// The server wasn't started with `--upload` or `--upload-overwrite`
+    UploadDisabled,
+    /// Overwriting was asked for, but the server wasn't started with `--upload-overwrite`
+    OverwriteDisabled,
+    /// The server was started with `--no-content-search`
+    ContentSearchDisabled,
+    /// It can't be done there, like searching inside an archive or uploading to the virtual directory
+    NotSupported,
+    /// The archive is corrupt, or isn't really an archive
+    UnreadableArchive,
+    /// Reading from the disk failed
+    Io,
+}
+
+/// Every error is returned as this, with a status code that goes with the kind
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ApiError {
+    error: ErrorKind,
+    /// Meant for people, could change at any time
+    message: String,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(error: ErrorKind, message: S) -> Self {
+        Self { error, message: message.into() }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns how the page urls answer with the error, which is just the status code and the message
+    pub fn into_page_response(self) -> Response {
+        (self.status_code(), self.message).into_response()
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            ErrorKind::NotWhitelisted => StatusCode::UNAUTHORIZED,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidQuery | ErrorKind::NotADirectory => StatusCode::BAD_REQUEST,
+            ErrorKind::UploadDisabled => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::OverwriteDisabled => StatusCode::CONFLICT,
+            ErrorKind::ContentSearchDisabled | ErrorKind::NotSupported => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::UnreadableArchive => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Io => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status_code(), Json(self)).into_response()
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(ErrorKind::InvalidQuery, rejection.body_text())
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(rejection: MultipartRejection) -> Self {
+        ApiError::new(ErrorKind::InvalidQuery, rejection.body_text())
+    }
+}
+
+/// Returns the api's routes, which take precedence over any served directory called `api`
+pub fn router() -> Router<AppState> {
+    let router = Router::new()
+        .route(&format!("{PREFIX}/openapi.json"), get(openapi))
+        .route(PREFIX, any(not_found))
+        .route(&format!("{PREFIX}/"), any(not_found))
+        .route(&format!("{PREFIX}/*rest"), any(not_found));
+    [
+        ("list", get(list)),
+        ("stat", get(stat)),
+        ("tree", get(tree)),
+        ("manifest", get(manifest)),
+        ("search", get(search_names)),
+        ("grep", get(grep)),
+        ("upload", post(upload)),
+    ].into_iter().fold(router, |router, (endpoint, method_router): (&str, MethodRouter<AppState>)| {
+        // the path is optional, so the root can be asked for with or without a slash
+        router
+            .route(&format!("{PREFIX}/{endpoint}"), method_router.clone())
+            .route(&format!("{PREFIX}/{endpoint}/"), method_router.clone())
+            .route(&format!("{PREFIX}/{endpoint}/*path"), method_router)
+    })
+}
+
+fn authorize(state: &AppState, addr: SocketAddr) -> Result<(), ApiError> {
+    if ip_authorized(state, &addr.ip()) {
+        return Ok(())
+    }
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[0;2m tried to use the api but isn't whitelisted\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
+    Err(ApiError::new(ErrorKind::NotWhitelisted, format!("{} isn't whitelisted", addr.ip())))
+}
+
+/// Returns the (encoded) path an endpoint was called with, like `/docs` for `/api/v1/list/docs`
+fn get_request_path(uri: &Uri, endpoint: &str) -> String {
+    let path = uri.path().strip_prefix(PREFIX).and_then(|i| i.strip_prefix('/')).and_then(|i| i.strip_prefix(endpoint)).unwrap_or_default();
+    url_encoding::normalize_path(if path.starts_with('/') { path.to_string() } else { String::from("/") + path })
+}
+
+/// Works out what a path points to, which unlike page urls doesn't need a trailing slash to be a directory,
+/// and returns it with the request path as a page url would have it
+///
+/// An archive is both a file and a directory, `prefer_directory` decides which it's treated as
+async fn resolve(state: &AppState, request_path: &str, prefer_directory: bool) -> Result<(FiguredOutRequestPath, String), ApiError> {
+    let file_path = request_path.trim_end_matches('/').to_string();
+    let directory_path = file_path.clone() + "/";
+    let candidates = if prefer_directory { [directory_path, file_path] } else { [file_path, directory_path] };
+    let resolve_state = state.clone();
+    let resolved = run_blocking(state, move || {
+        candidates.into_iter().map(|i| (figure_out_request_path(&resolve_state, &i), i)).find(|(figured_out_path, _)| *figured_out_path != FiguredOutRequestPath::NotFound)
+    }).await;
+    resolved.ok_or_else(|| ApiError::new(ErrorKind::NotFound, format!("{} doesn't exist", url_encoding::decode_lossy(request_path))))
+}
+
+/// Returns the OpenAPI document describing this api
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    responses(
+        (status = 200, description = "The OpenAPI document", content_type = "application/json"),
+        (status = 401, description = "The client isn't whitelisted", body = ApiError),
+    ),
+)]
+async fn openapi(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>) -> Result<Response, ApiError> {
+    authorize(&state, addr)?;
+    let mut openapi = ApiDoc::openapi();
+    // it's taken from Cargo.toml, which doesn't have one, and an empty license isn't valid
+    openapi.info.license = None;
+    // the paths are relative to this, and they'd be missing the base path without it
+    if !state.base_path.is_empty() {
+        openapi.servers = Some(vec![Server::new(&state.base_path)]);
+    }
+    Ok(Json(openapi).into_response())
+}
+
+async fn not_found(uri: Uri) -> ApiError {
+    ApiError::new(ErrorKind::NotFound, format!("There's no {} endpoint", uri.path()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// Directories always come first
+    sort: Option<SortKey>,
+    /// Defaults to ascending for names, and descending (newest and largest first) for the others
+    order: Option<SortOrder>,
+    /// Only entries with this in their name (ignoring case) are listed
+    filter: Option<String>,
+    offset: Option<usize>,
+    /// Everything is listed if there's no limit
+    limit: Option<usize>,
+}
+
+/// A page of a directory's entries
+#[derive(Serialize, ToSchema)]
+struct Listing {
+    /// The entries' urls are always absolute, and files can be downloaded from them
+    entries: Vec<EntryData>,
+    /// How many entries there are (after filtering), `entries` is only part of them if it's fewer
+    total: usize,
+}
+
+/// Lists a directory, the virtual directory, or a directory inside an archive (including the archive itself)
+#[utoipa::path(
+    get,
+    path = "/api/v1/list/{path}",
+    params(("path" = String, Path, description = "The directory to list"), ListQuery),
+    responses(
+        (status = 200, description = "The requested page of the entries", body = Listing),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn list(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<ListQuery>, QueryRejection>) -> Result<Json<Listing>, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "list"), true).await?;
+    let listing_options = ListingOptions::new(query.sort, query.order, query.filter, query.offset, query.limit);
+    let (entries, total) = match &figured_out_path {
+        FiguredOutRequestPath::Directory(_) | FiguredOutRequestPath::VirtualDirectory => {
+            let listing_state = state.clone();
+            let listing_request_path = request_path.clone();
+            run_blocking(&state, move || {
+                let path_segments = listing_request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+                let (entries, total, _) = get_directory_entries(&listing_state, &figured_out_path, &path_segments, true, &listing_options);
+                (entries, total)
+            }).await
+        },
+        FiguredOutRequestPath::ArchiveDirectory(archive_path, inner_path) => {
+            let archive_entries = operations::read_archive(&state, archive_path).await?;
+            let archive_entries = archive::directory_entries(&archive_entries, inner_path)
+                .ok_or_else(|| ApiError::new(ErrorKind::NotFound, format!("{} doesn't exist", url_encoding::decode_lossy(&request_path))))?;
+            let path_segments = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+            get_archive_entries(&state, archive_entries, &path_segments, true, &listing_options)
+        },
+        _ => return Err(ApiError::new(ErrorKind::NotADirectory, format!("{} is a file", url_encoding::decode_lossy(&request_path)))),
+    };
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path());
+    Ok(Json(Listing { entries, total }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatQuery {
+    /// Comma-separated hashes to return, `sha256` and `sha512` are supported. Only files on disk can be hashed
+    hash: Option<String>,
+}
+
+/// Returns the metadata of a file or directory, including ones inside archives
+#[utoipa::path(
+    get,
+    path = "/api/v1/stat/{path}",
+    params(("path" = String, Path, description = "The file or directory"), StatQuery),
+    responses(
+        (status = 200, description = "The metadata, with only what the archive keeps track of for entries inside archives", body = FileMetadata),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn stat(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<StatQuery>, QueryRejection>) -> Result<Json<FileMetadata>, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "stat"), false).await?;
+    let metadata = operations::metadata(&state, figured_out_path, &request_path, query.hash.as_deref()).await?;
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path());
+    Ok(Json(metadata))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TreeQuery {
+    /// How many levels of directories to go down, up to 8 (which is also what it is without one)
+    depth: Option<usize>,
+}
+
+/// Returns everything inside a directory (or the virtual directory) as nested entries, with directory sizes and modification times
+#[utoipa::path(
+    get,
+    path = "/api/v1/tree/{path}",
+    params(("path" = String, Path, description = "The directory"), TreeQuery),
+    responses(
+        (status = 200, description = "The directory, with what's inside it as its children", body = TreeEntry),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn tree(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<TreeQuery>, QueryRejection>) -> Result<Json<TreeEntry>, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "tree"), true).await?;
+    operations::walkable(&figured_out_path, &request_path)?;
+    let tree_state = state.clone();
+    let url = absolute_url(&state, &request_path);
+    let tree = run_blocking(&state, move || match &figured_out_path {
+        FiguredOutRequestPath::Directory(path) => {
+            let name = path.file_name().map(|i| i.to_string_lossy().to_string()).unwrap_or(String::from("Root Directory"));
+            tree::directory_tree(&tree_state, path, name, url, query.depth)
+        },
+        _ => tree::virtual_directory_tree(&tree_state, String::from("Virtual Directory"), query.depth),
+    }).await;
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path());
+    Ok(Json(tree))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ManifestQuery {
+    /// Comma-separated hashes to return for every file, `sha256` and `sha512` are supported
+    hash: Option<String>,
+}
+
+/// Returns every file inside a directory (or the virtual directory) with its path relative to it, for mirroring
+#[utoipa::path(
+    get,
+    path = "/api/v1/manifest/{path}",
+    params(("path" = String, Path, description = "The directory"), ManifestQuery),
+    responses(
+        (status = 200, description = "The files, sorted by path", body = Vec<ManifestEntry>),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn manifest(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<ManifestQuery>, QueryRejection>) -> Result<Json<Vec<ManifestEntry>>, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "manifest"), true).await?;
+    let manifest = operations::manifest(&state, figured_out_path, &request_path, query.hash.as_deref()).await?;
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path());
+    Ok(Json(manifest))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+    /// What to look for in names
+    q: String,
+    mode: Option<SearchMode>,
+    /// Defaults to 1000
+    limit: Option<usize>,
+}
+
+/// Searches for files and directories by name, everywhere inside the directory
+#[utoipa::path(
+    get,
+    path = "/api/v1/search/{path}",
+    params(("path" = String, Path, description = "The directory to search"), SearchQuery),
+    responses(
+        (status = 200, description = "Each match on its own line, streamed as they're found", body = EntryData, content_type = "application/x-ndjson"),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn search_names(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<SearchQuery>, QueryRejection>) -> Result<Response, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    if query.q.is_empty() {
+        return Err(ApiError::new(ErrorKind::InvalidQuery, "q can't be empty"))
+    }
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "search"), true).await?;
+    operations::walkable(&figured_out_path, &request_path)?;
+    let roots_state = state.clone();
+    let roots = run_blocking(&state, move || get_search_roots(&roots_state, &figured_out_path, &request_path)).await.unwrap_or_default();
+    let permit = filesystem_permit(&state).await;
+    let body = search::search(state, permit, roots, &query.q, query.mode.unwrap_or_default(), query.limit.unwrap_or(search::DEFAULT_LIMIT))
+        .map_err(|e| ApiError::new(ErrorKind::InvalidQuery, e.to_string()))?;
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {} \x1b[2m(search: {})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path(), query.q);
+    Ok(ndjson_response(body))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GrepQuery {
+    /// The words to look for in text files, the last one can be the start of a word
+    q: String,
+    /// Defaults to 100 files
+    limit: Option<usize>,
+}
+
+/// Searches the contents of the text files inside the directory
+#[utoipa::path(
+    get,
+    path = "/api/v1/grep/{path}",
+    params(("path" = String, Path, description = "The directory to search"), GrepQuery),
+    responses(
+        (status = 200, description = "The files with a match", body = GrepResults),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn grep(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<GrepQuery>, QueryRejection>) -> Result<Json<GrepResults>, ApiError> {
+    authorize(&state, addr)?;
+    let Query(query) = query?;
+    if query.q.is_empty() {
+        return Err(ApiError::new(ErrorKind::InvalidQuery, "q can't be empty"))
+    }
+    let (figured_out_path, request_path) = resolve(&state, &get_request_path(&uri, "grep"), true).await?;
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {} \x1b[2m(grep: {})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), uri.path(), query.q);
+    let results = operations::grep(&state, figured_out_path, &request_path, query.q, query.limit).await?;
+    Ok(Json(results))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UploadQuery {
+    /// Create the directory, and any missing directories above it, if it doesn't exist
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    create_directories: bool,
+    /// Fail instead of renaming files if the server doesn't overwrite them. Clients that need to replace files can upload nothing
+    /// with this to check first
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    overwrite: bool,
+}
+
+/// Uploads files into the directory, which are renamed if a file with the same name exists, unless the server overwrites files
+#[utoipa::path(
+    post,
+    path = "/api/v1/upload/{path}",
+    params(("path" = String, Path, description = "The directory to upload to"), UploadQuery),
+    request_body(content_type = "multipart/form-data", description = "A field with a filename for each file"),
+    responses(
+        (status = 201, description = "The names the files were saved as", body = Vec<String>),
+        (status = "4XX", description = "The request couldn't be done, see the error's kind", body = ApiError),
+        (status = "5XX", description = "Reading from the disk failed", body = ApiError),
+    ),
+)]
+async fn upload(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, query: Result<Query<UploadQuery>, QueryRejection>, multipart: Result<Multipart, MultipartRejection>) -> Result<(StatusCode, Json<Vec<String>>), ApiError> {
+    authorize(&state, addr)?;
+    if !(state.upload || state.upload_overwrite) {
+        return Err(ApiError::new(ErrorKind::UploadDisabled, "Uploading is turned off"))
+    }
+    let Query(query) = query?;
+    if query.overwrite && !state.upload_overwrite {
+        return Err(ApiError::new(ErrorKind::OverwriteDisabled, "The server renames uploads instead of overwriting files (it needs --upload-overwrite)"))
+    }
+    let mut multipart = multipart?;
+    let request_path = get_request_path(&uri, "upload");
+    let (figured_out_path, request_path) = match resolve(&state, &request_path, true).await {
+        Err(e) if e.error == ErrorKind::NotFound && query.create_directories => create_directories(&state, &request_path).await?,
+        resolved => resolved?,
+    };
+    let upload_directory = match figured_out_path {
+        FiguredOutRequestPath::Directory(path) => path,
+        FiguredOutRequestPath::VirtualDirectory => return Err(ApiError::new(ErrorKind::NotSupported, "Files can't be uploaded to the virtual directory")),
+        FiguredOutRequestPath::ArchiveDirectory(..) => return Err(ApiError::new(ErrorKind::NotSupported, "Files can't be uploaded into archives")),
+        _ => return Err(ApiError::new(ErrorKind::NotADirectory, format!("{} is a file", url_encoding::decode_lossy(&request_path)))),
+    };
+    let new_filenames = receive_uploads(&state, addr, &url_encoding::decode_lossy(&request_path), &upload_directory, &mut multipart).await
+        .map_err(|e| ApiError::new(ErrorKind::Io, e))?;
+    Ok((StatusCode::CREATED, Json(new_filenames)))
+}
+
+/// Creates the directories in `request_path` that don't exist yet, inside the closest one that does, and returns it like `resolve` does
+async fn create_directories(state: &AppState, request_path: &str) -> Result<(FiguredOutRequestPath, String), ApiError> {
+    let components = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+    for index in (0..components.len()).rev() {
+        let existing_path = String::from("/") + &components[..index].join("/");
+        let Ok((figured_out_path, _)) = resolve(state, &existing_path, true).await else { continue };
+        let FiguredOutRequestPath::Directory(existing_directory) = figured_out_path else {
+            return Err(ApiError::new(ErrorKind::NotSupported, "Directories can only be created inside served directories"))
+        };
+        let Some(missing) = decode_path_components(&components[index..].join("/")).filter(|i| !i.iter().any(|i| i == ".")) else {
+            return Err(ApiError::new(ErrorKind::InvalidQuery, format!("{} isn't a valid path", url_encoding::decode_lossy(request_path))))
+        };
+        let new_directory = existing_directory.join(missing.iter().collect::<PathBuf>());
+        run_blocking(state, move || fs::create_dir_all(new_directory)).await
+            .map_err(|e| ApiError::new(ErrorKind::Io, format!("Couldn't create {}: {e}", url_encoding::decode_lossy(request_path))))?;
+        return resolve(state, request_path, true).await
+    }
+    Err(ApiError::new(ErrorKind::NotFound, format!("{} doesn't exist", url_encoding::decode_lossy(request_path))))
+}
@@ -0,0 +1,359 @@
+use std::{collections::HashMap, fmt, fs::File, io::{self, BufReader, Read, Seek, SeekFrom}, path::Path, sync::Arc};
+use axum::body::{Body, Bytes};
+use chrono::NaiveDate;
+use tokio::sync::Semaphore;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::{blocking_filesystem_permit, versioned_cache::{file_version, VersionedCache}};
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+// listing a directory full of archives would otherwise keep the entries of every one of them forever
+const MAX_CACHED_INDEXES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// A file or directory inside an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// The path inside the archive, without leading or trailing slashes
+    pub path: String,
+    pub directory: bool,
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub modified: f32,
+    /// Where the file is in the archive, the index for zip files and where its contents start for tar files, None for anything
+    /// that can't be read (directories, links)
+    position: Option<u64>,
+}
+
+impl ArchiveEntry {
+    /// Returns the last component of the path
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap()
+    }
+}
+
+/// Returns which kind of archive the file is, going by its name
+pub fn archive_kind<P: AsRef<Path>>(path: P) -> Option<ArchiveKind> {
+    let name = path.as_ref().file_name()?.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// The entries of archives that have been read, so going through one doesn't read (and for .tar.gz decompress) all of it again
+/// for every listing, page and file
+#[derive(Debug, Clone)]
+pub struct ArchiveIndexes {
+    indexes: VersionedCache<Arc<Vec<ArchiveEntry>>>,
+}
+
+impl Default for ArchiveIndexes {
+    fn default() -> Self {
+        Self { indexes: VersionedCache::new(MAX_CACHED_INDEXES) }
+    }
+}
+
+impl ArchiveIndexes {
+    /// Returns every entry in the archive, only reading it if it changed since the last time
+    pub fn entries(&self, archive: &Path) -> io::Result<Arc<Vec<ArchiveEntry>>> {
+        let version = file_version(&archive.metadata()?);
+        if let Some(entries) = self.indexes.get(archive, version) {
+            return Ok(entries)
+        }
+        let entries = Arc::new(entries(archive)?);
+        self.indexes.insert(archive, version, entries.clone());
+        Ok(entries)
+    }
+}
+
+// archives made on windows or with `tar -C . ...` have all sorts of prefixes, and `..` can't go above the top of the archive
+// (which is what extracting them does too)
+fn normalize_path(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {},
+            ".." => { components.pop(); },
+            _ => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Returns every entry in the archive
+fn entries(archive: &Path) -> io::Result<Vec<ArchiveEntry>> {
+    let Some(kind) = archive_kind(archive) else { return Err(io::ErrorKind::Unsupported.into()) };
+    let mut entries = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+            for index in 0..zip.len() {
+                // raw so nothing gets decompressed just to be listed
+                let file = zip.by_index_raw(index)?;
+                let modified = file.last_modified().and_then(|i| {
+                    NaiveDate::from_ymd_opt(i.year().into(), i.month().into(), i.day().into())?
+                        .and_hms_opt(i.hour().into(), i.minute().into(), i.second().into())
+                }).map(|i| i.and_utc().timestamp() as f32).unwrap_or(0.0);
+                let position = file.is_file().then_some(index as u64);
+                entries.push(ArchiveEntry { path: normalize_path(file.name()), directory: file.is_dir(), size: file.size(), modified, position });
+            }
+        },
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = tar::Archive::new(tar_reader(archive, kind)?);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                let header = entry.header();
+                entries.push(ArchiveEntry {
+                    path: normalize_path(&entry.path()?.to_string_lossy()),
+                    directory: header.entry_type().is_dir(),
+                    size: header.size()?,
+                    modified: header.mtime().unwrap_or(0) as f32,
+                    position: header.entry_type().is_file().then(|| entry.raw_file_position()),
+                });
+            }
+        },
+    }
+    entries.retain(|i| !i.path.is_empty());
+    // tar files can have the same path more than once (like after `tar -r`), and extracting them leaves the last one
+    let last_indexes = entries.iter().enumerate().map(|(index, entry)| (entry.path.clone(), index)).collect::<HashMap<_, _>>();
+    let entries = entries.into_iter().enumerate().filter(|(index, entry)| last_indexes[&entry.path] == *index).map(|(_, entry)| entry).collect();
+    Ok(entries)
+}
+
+fn tar_reader(archive: &Path, kind: ArchiveKind) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(archive)?);
+    Ok(if kind == ArchiveKind::TarGz { Box::new(flate2::read::GzDecoder::new(file)) } else { Box::new(file) })
+}
+
+/// Returns the entries directly inside `directory` (a path inside the archive), or None if there's no such directory
+///
+/// Archives don't always have entries for directories, so those are also made up from the paths of the files inside them
+pub fn directory_entries(entries: &[ArchiveEntry], directory: &str) -> Option<Vec<ArchiveEntry>> {
+    let directory = normalize_path(directory);
+    let prefix = if directory.is_empty() { String::new() } else { directory.clone() + "/" };
+    let mut found = directory.is_empty();
+    let mut children: HashMap<String, ArchiveEntry> = HashMap::new();
+    for entry in entries {
+        if entry.path == directory && entry.directory {
+            found = true;
+        }
+        let Some(rest) = entry.path.strip_prefix(&prefix) else { continue };
+        found = true;
+        match rest.split_once('/') {
+            None => {
+                // an explicit directory entry has a better mtime than a made up one
+                children.insert(rest.to_string(), entry.clone());
+            },
+            Some((name, _)) => {
+                children.entry(name.to_string()).or_insert_with(|| ArchiveEntry { path: prefix.clone() + name, directory: true, size: 0, modified: entry.modified, position: None });
+            },
+        }
+    }
+    if !found {
+        return None
+    }
+    // directories don't have a size of their own in archives, so they get the combined size of their contents like real directories
+    let mut children = children.into_values().collect::<Vec<_>>();
+    for child in children.iter_mut().filter(|i| i.directory) {
+        let child_prefix = child.path.clone() + "/";
+        child.size = entries.iter().filter(|i| !i.directory && i.path.starts_with(&child_prefix)).map(|i| i.size).sum();
+    }
+    Some(children)
+}
+
+/// Returns the file or directory at `path` (a path inside the archive), with directories made up like `directory_entries` does
+pub fn entry(entries: &[ArchiveEntry], path: &str) -> Option<ArchiveEntry> {
+    let path = normalize_path(path);
+    if let Some(file) = entries.iter().find(|i| i.path == path && !i.directory) {
+        return Some(file.clone())
+    }
+    directory_entries(entries, &path)?;
+    let prefix = path.clone() + "/";
+    Some(ArchiveEntry {
+        size: entries.iter().filter(|i| !i.directory && i.path.starts_with(&prefix)).map(|i| i.size).sum(),
+        modified: entries.iter().find(|i| i.path == path && i.directory).map(|i| i.modified).unwrap_or(0.0),
+        path,
+        directory: true,
+        position: None,
+    })
+}
+
+/// Calls `read` with the contents of `entry`, which has to be a file from `entries`
+fn read_entry(archive: &Path, entry: &ArchiveEntry, read: impl FnOnce(&mut dyn Read)) -> io::Result<()> {
+    let (Some(kind), Some(position)) = (archive_kind(archive), entry.position) else { return Err(io::ErrorKind::Unsupported.into()) };
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+            read(&mut zip.by_index(position as usize)?);
+        },
+        ArchiveKind::Tar => {
+            let mut file = BufReader::new(File::open(archive)?);
+            file.seek(SeekFrom::Start(position))?;
+            read(&mut file.take(entry.size));
+        },
+        ArchiveKind::TarGz => {
+            // there's no seeking in gzip, but skipping to the file still saves reading every header on the way
+            let mut reader = tar_reader(archive, kind)?;
+            io::copy(&mut reader.by_ref().take(position), &mut io::sink())?;
+            read(&mut reader.take(entry.size));
+        },
+    }
+    Ok(())
+}
+
+/// Why a file inside an archive couldn't be streamed
+#[derive(Debug)]
+pub enum StreamFileError {
+    /// The archive couldn't be read
+    Unreadable(io::Error),
+    /// Reading it panicked, which isn't the archive's fault
+    Panicked,
+}
+
+impl fmt::Display for StreamFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreadable(e) => write!(f, "couldn't read archive: {e}"),
+            Self::Panicked => write!(f, "reading the archive panicked"),
+        }
+    }
+}
+
+/// Returns the size of the file at `inner_path` and a body that streams it, or None if there's no such file
+///
+/// The file is found with one of the `--fs-jobs` `permits`, and each chunk is read with another, so a client that's slow to take them
+/// isn't holding one
+pub async fn stream_file(indexes: &ArchiveIndexes, archive: &Path, inner_path: &str, permits: Arc<Semaphore>) -> Result<Option<(u64, Body)>, StreamFileError> {
+    let (indexes, archive, inner_path) = (indexes.clone(), archive.to_path_buf(), normalize_path(inner_path));
+    let (chunk_sender, chunk_receiver) = tokio::sync::mpsc::channel::<io::Result<Bytes>>(4);
+    let (found_sender, found_receiver) = tokio::sync::oneshot::channel();
+    let permit = permits.clone().acquire_owned().await.unwrap();
+    // archives are read with blocking io (and zip files can't be read without it), so the chunks are sent over from another thread
+    tokio::task::spawn_blocking(move || {
+        let mut permit = Some(permit);
+        let mut found_sender = Some(found_sender);
+        let result = indexes.entries(&archive).and_then(|entries| {
+            let Some(entry) = entries.iter().find(|i| i.path == inner_path && i.position.is_some()) else { return Ok(()) };
+            read_entry(&archive, entry, |reader| {
+                drop(permit.take());
+                let _ = found_sender.take().unwrap().send(Ok(Some(entry.size)));
+                let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+                loop {
+                    let chunk_permit = blocking_filesystem_permit(&permits);
+                    let read = reader.read(&mut buffer);
+                    drop(chunk_permit);
+                    match read {
+                        Ok(0) => break,
+                        Ok(length) => if chunk_sender.blocking_send(Ok(Bytes::copy_from_slice(&buffer[..length]))).is_err() {
+                            // the client went away
+                            break
+                        },
+                        Err(e) => {
+                            let _ = chunk_sender.blocking_send(Err(e));
+                            break
+                        },
+                    }
+                }
+            })
+        });
+        if let Some(found_sender) = found_sender {
+            let _ = found_sender.send(result.map(|_| None));
+        }
+    });
+    // the sender is only dropped without sending anything if the thread panicked
+    let found = found_receiver.await.map_err(|_| StreamFileError::Panicked)?;
+    let Some(size) = found.map_err(StreamFileError::Unreadable)? else { return Ok(None) };
+    Ok(Some((size, Body::from_stream(ReceiverStream::new(chunk_receiver)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::temporary_directory::TemporaryDirectory;
+    use super::*;
+
+    fn file(path: &str, size: u64) -> ArchiveEntry {
+        ArchiveEntry { path: normalize_path(path), directory: false, size, modified: 0.0, position: Some(0) }
+    }
+
+    fn names(entries: Option<Vec<ArchiveEntry>>) -> Vec<String> {
+        let mut names = entries.unwrap().iter().map(|i| i.name().to_string()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Writes a tar file with `files` (which can repeat paths) to `path`, gzipped if `kind` is TarGz
+    fn write_tar(path: &Path, kind: ArchiveKind, files: &[(&str, &str)]) {
+        let file = File::create(path).unwrap();
+        let writer: Box<dyn io::Write> = if kind == ArchiveKind::TarGz { Box::new(flate2::write::GzEncoder::new(file, flate2::Compression::fast())) } else { Box::new(file) };
+        let mut builder = tar::Builder::new(writer);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().flush().unwrap();
+    }
+
+    fn read_to_string(archive: &Path, entries: &[ArchiveEntry], path: &str) -> String {
+        let mut contents = String::new();
+        read_entry(archive, entries.iter().find(|i| i.path == path).unwrap(), |reader| { reader.read_to_string(&mut contents).unwrap(); }).unwrap();
+        contents
+    }
+
+    #[test]
+    fn paths_stay_inside_the_archive() {
+        assert_eq!(normalize_path("../../etc/passwd"), "etc/passwd");
+        assert_eq!(normalize_path("docs/../../readme.txt"), "readme.txt");
+        assert_eq!(normalize_path("/absolute/path.txt"), "absolute/path.txt");
+        assert_eq!(normalize_path("C:\\windows\\.\\file.txt"), "C:/windows/file.txt");
+        assert_eq!(normalize_path("./.."), "");
+
+        let entries = [file("../up.txt", 1), file("/root.txt", 2), file("/docs/a.txt", 3), file("docs/../b.txt", 4)];
+        assert_eq!(names(directory_entries(&entries, "")), ["b.txt", "docs", "root.txt", "up.txt"]);
+        assert_eq!(names(directory_entries(&entries, "/docs/")), ["a.txt"]);
+        assert_eq!(names(directory_entries(&entries, "../docs")), ["a.txt"]);
+        assert!(directory_entries(&entries, "missing").is_none());
+        assert_eq!(entry(&entries, "docs").unwrap().size, 3);
+    }
+
+    #[test]
+    fn the_last_duplicate_is_used() {
+        let directory = TemporaryDirectory::new("archive");
+        for (kind, name) in [(ArchiveKind::Tar, "duplicates.tar"), (ArchiveKind::TarGz, "duplicates.tar.gz")] {
+            let archive = directory.join(name);
+            write_tar(&archive, kind, &[("a.txt", "first"), ("docs/b.txt", "b"), ("./a.txt", "second!")]);
+            let entries = ArchiveIndexes::default().entries(&archive).unwrap();
+            assert_eq!(names(directory_entries(&entries, "")), ["a.txt", "docs"]);
+            assert_eq!(entry(&entries, "a.txt").unwrap().size, 7);
+            assert_eq!(read_to_string(&archive, &entries, "a.txt"), "second!");
+            assert_eq!(read_to_string(&archive, &entries, "docs/b.txt"), "b");
+        }
+    }
+
+    #[test]
+    fn indexes_are_read_again_when_the_archive_changes() {
+        let indexes = ArchiveIndexes::default();
+        let directory = TemporaryDirectory::new("archive");
+        let archive = directory.join("changes.tar");
+        write_tar(&archive, ArchiveKind::Tar, &[("a.txt", "a")]);
+        let entries = indexes.entries(&archive).unwrap();
+        assert!(Arc::ptr_eq(&entries, &indexes.entries(&archive).unwrap()));
+
+        write_tar(&archive, ArchiveKind::Tar, &[("a.txt", "a"), ("b.txt", "b")]);
+        File::options().write(true).open(&archive).unwrap().set_modified(std::time::SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(names(directory_entries(&indexes.entries(&archive).unwrap(), "")), ["a.txt", "b.txt"]);
+    }
+}
@@ -1,5 +1,6 @@
-use std::path::PathBuf;
-use clap::{Parser, ValueEnum};
+use std::{ffi::OsString, net::IpAddr, num::NonZeroUsize, path::PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize)]
@@ -8,13 +9,82 @@ pub enum DirectoryListingViewType {
 	Grid,
 	List,
 	CompactList,
+	Gallery,
+}
+
+/// The header trusted proxies put the client's address in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ForwardedHeader {
+	/// `X-Forwarded-For`, which nginx's `$proxy_add_x_forwarded_for` and most other proxies add to
+	Xff,
+	/// `Forwarded` (RFC 7239)
+	Forwarded,
+	/// `X-Real-IP`, which the proxy has to set rather than pass on
+	RealIp,
+}
+
+/// A directory or file on a server, for the client commands
+#[derive(clap::Args, Debug, Clone)]
+pub struct RemoteUrl {
+	pub url: String,
+
+	/// The server's --base-path, which the api is under [default: asked from the server]
+	#[arg(long, value_parser=parse_base_path)]
+	pub base_path: Option<String>,
+}
+
+/// Talks to a gshare3 server instead of starting one
+#[derive(Subcommand, Debug, Clone)]
+pub enum ClientCommand {
+	/// Download a file, continuing where it left off if it was partly downloaded
+	Get {
+		#[command(flatten)]
+		remote: RemoteUrl,
+		/// Where to save it [default: the file's name, in the current directory]
+		#[arg(short, long)]
+		output: Option<PathBuf>,
+	},
+	/// Upload files to a directory (the server has to allow uploads)
+	Put {
+		#[command(flatten)]
+		remote: RemoteUrl,
+		#[arg(required=true)]
+		files: Vec<PathBuf>,
+	},
+	/// List a directory
+	Ls {
+		#[command(flatten)]
+		remote: RemoteUrl,
+	},
+	/// Download everything inside a directory, skipping files that are already up to date
+	Mirror {
+		#[command(flatten)]
+		remote: RemoteUrl,
+		/// Where to download to [default: the current directory]
+		directory: Option<PathBuf>,
+	},
+	/// Sync a local directory with a directory on a server both ways (the server has to allow overwriting files)
+	Sync {
+		directory: PathBuf,
+		#[command(flatten)]
+		remote: RemoteUrl,
+	},
+	/// List the gshare3 servers on the local network
+	Discover {
+		/// How many seconds to listen for
+		#[arg(short, long, default_value="3")]
+		timeout: f64,
+	},
 }
 
 #[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands=true)]
 pub struct Args {
+	#[command(subcommand)]
+	pub command: Option<ClientCommand>,
+
 	/// Files/dirs served and IP addresses that can access the website
-	pub paths_and_ips: Vec<String>,
+	pub paths_and_ips: Vec<OsString>,
 
 	/// Allow clients to upload *new* files
 	#[arg(short, long, conflicts_with="upload_overwrite")]
@@ -40,10 +110,73 @@ pub struct Args {
 	#[arg(long)]
 	pub private: bool,
 
+	/// Listen on a unix socket instead of a port, for running behind a reverse proxy (client addresses come from --forwarded-header)
+	#[arg(long, conflicts_with_all=["bind", "private", "qr"])]
+	pub unix_socket: Option<PathBuf>,
+
+	/// Take client addresses from the --forwarded-header of requests from these addresses or networks, like `127.0.0.1` or `10.0.0.0/8`
+	/// (can be given more than once)
+	#[arg(long, value_parser=parse_trusted_proxy)]
+	pub trusted_proxy: Vec<IpNet>,
+
+	/// The header trusted proxies (and unix socket clients) put the client's address in. The others are ignored, since a proxy passes on
+	/// whatever the client sent in the headers it doesn't set itself
+	#[arg(long, value_enum, default_value_t=ForwardedHeader::Xff)]
+	pub forwarded_header: ForwardedHeader,
+
+	/// Serve everything under this path, for a reverse proxy that doesn't strip it, like `/files`
+	#[arg(long, default_value="", value_parser=parse_base_path)]
+	pub base_path: String,
+
+	/// Listen on these addresses instead of every interface (can be given more than once)
+	#[arg(long, conflicts_with="private")]
+	pub bind: Vec<IpAddr>,
+
+	/// Show a QR code of the server's url, for opening it on a phone
+	#[arg(long)]
+	pub qr: bool,
+
+	/// Disable advertising the server on the local network with mDNS
+	#[arg(long)]
+	pub no_mdns: bool,
+
 	/// Disable recursive directory size finding
 	#[arg(long="no-dir-sizes")]
 	pub no_directory_sizes: bool,
 
+	/// Set how many directories are read at once when finding directory sizes [default: number of CPUs]
+	#[arg(long="dir-size-threads")]
+	pub directory_size_threads: Option<usize>,
+
+	/// Set where directory sizes are cached between runs [default: a file for the shared paths in gshare3 in the user's cache directory]
+	#[arg(long="dir-size-cache", conflicts_with="no_directory_size_cache")]
+	pub directory_size_cache: Option<PathBuf>,
+
+	/// Disable caching directory sizes between runs
+	#[arg(long="no-dir-size-cache")]
+	pub no_directory_size_cache: bool,
+
+	/// Set how many filesystem jobs (reading directories, finding paths, searching) can run at once, more wait for their turn
+	#[arg(long="fs-jobs", default_value="64")]
+	pub filesystem_jobs: NonZeroUsize,
+
+	/// Disable indexing the contents of text files, which is needed to search them
+	#[arg(long)]
+	pub no_content_search: bool,
+
+	/// Disable image thumbnails in the grid view
+	#[arg(long)]
+	pub no_thumbnails: bool,
+
+	/// Set where thumbnails are cached, which is kept under 256 MB by removing the ones used the longest ago [default: gshare3/thumbnails in
+	/// the user's cache directory]
+	#[arg(long, conflicts_with="no_thumbnail_cache")]
+	pub thumbnail_cache: Option<PathBuf>,
+
+	/// Disable caching thumbnails, they're generated every time instead
+	#[arg(long)]
+	pub no_thumbnail_cache: bool,
+
 	/// Set the default directory listing view
 	#[arg(short='v', long, default_value="grid")]
 	pub default_view: DirectoryListingViewType,
@@ -53,6 +186,21 @@ pub struct Args {
 	pub title: Option<String>,
 }
 
+fn parse_trusted_proxy(value: &str) -> Result<IpNet, String> {
+	value.parse::<IpNet>()
+		.or_else(|_| value.parse::<IpAddr>().map(|i| IpNet::from(i.to_canonical())))
+		.map_err(|_| format!("{value} isn't an address or a network like 10.0.0.0/8"))
+}
+
+/// Makes the base path start with a slash and not end with one, so urls can be appended to it, with `/` being the same as none
+fn parse_base_path(value: &str) -> Result<String, String> {
+	let path = value.trim_matches('/');
+	if path.contains(|i: char| matches!(i, ':' | '*' | '?' | '#' | '%') || i.is_whitespace()) {
+		return Err(String::from("it can only have characters that don't need to be encoded in urls"))
+	}
+	Ok(if path.is_empty() { String::new() } else { String::from("/") + path })
+}
+
 pub fn get_args() -> Args {
 	Args::parse()
 }
@@ -0,0 +1,317 @@
+use std::{collections::HashMap, fs, io::Write, path::{Component, Path, PathBuf}, process, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use chrono::{DateTime, Local, Utc};
+use reqwest::{header::{IF_UNMODIFIED_SINCE, RANGE}, multipart::{Form, Part}, Body, Client, Method, Response, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stream::wrappers::ReceiverStream;
+use crate::{cli::{ClientCommand, RemoteUrl}, discovery, preview::human_readable_size, sync, BASE_PATH_HEADER};
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An entry of `/api/v1/list`, with just what the client uses
+#[derive(Deserialize)]
+struct RemoteEntry {
+    name: String,
+    directory: bool,
+    size: Option<u64>,
+    modified: f64,
+}
+
+#[derive(Deserialize)]
+struct Listing {
+    entries: Vec<RemoteEntry>,
+}
+
+/// What `/api/v1/stat` returns, with just what the client uses
+#[derive(Deserialize)]
+pub struct RemoteMetadata {
+    name: String,
+    url: String,
+    directory: bool,
+    pub size: u64,
+    pub modified: f64,
+    #[serde(default)]
+    pub hashes: HashMap<String, String>,
+}
+
+/// An entry of `/api/v1/manifest`
+#[derive(Deserialize)]
+pub struct RemoteFile {
+    pub path: String,
+    pub url: String,
+    pub size: u64,
+    pub modified: f64,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+/// Runs a client command, exiting with an error message if it fails
+pub async fn run(command: ClientCommand) {
+    let client = Client::new();
+    let result = match command {
+        ClientCommand::Get { remote, output } => get(&client, &remote, output).await,
+        ClientCommand::Put { remote, files } => put(&client, &remote, &files).await,
+        ClientCommand::Ls { remote } => ls(&client, &remote).await,
+        ClientCommand::Mirror { remote, directory } => mirror(&client, &remote, &directory.unwrap_or(PathBuf::from("."))).await,
+        ClientCommand::Sync { directory, remote } => sync::sync(&client, &directory, &remote).await,
+        ClientCommand::Discover { timeout } => tokio::task::spawn_blocking(move || discovery::discover(Duration::from_secs_f64(timeout))).await.unwrap(),
+    };
+    if let Err(e) = result {
+        eprintln!("\x1b[91m{e}\x1b[0m");
+        process::exit(1);
+    }
+}
+
+/// Parses a url given on the command line, which doesn't need the `http://`
+pub fn parse_url(url: &str) -> Result<Url, String> {
+    let url = if url.contains("://") { url.to_string() } else { String::from("http://") + url };
+    Url::parse(&url).map_err(|e| format!("Invalid url {url}: {e}"))
+}
+
+/// Parses the url of a client command, and returns it with the server's base path (see `--base-path`), which is asked from the
+/// server unless it was given
+pub async fn resolve_url(client: &Client, remote: &RemoteUrl) -> Result<(Url, String), String> {
+    let url = parse_url(&remote.url)?;
+    let base_path = match &remote.base_path {
+        Some(base_path) => base_path.clone(),
+        // every response has it, and the server doesn't do anything for OPTIONS requests, so it's the cheapest one to ask with
+        None => {
+            let response = client.request(Method::OPTIONS, url.clone()).send().await.map_err(|e| e.to_string())?;
+            response.headers().get(BASE_PATH_HEADER).and_then(|i| i.to_str().ok()).unwrap_or_default().to_string()
+        },
+    };
+    Ok((url, base_path))
+}
+
+/// Returns the url of an api endpoint for the path in `url`, which the server sees without its `base_path`
+pub fn api_url(url: &Url, base_path: &str, endpoint: &str) -> Result<Url, String> {
+    let path = url.path().strip_prefix(base_path).filter(|i| i.is_empty() || i.starts_with('/'))
+        .ok_or_else(|| format!("{url} isn't under the server's base path, {base_path}/"))?;
+    url.join(&format!("{base_path}/api/v1/{endpoint}{}", if path.is_empty() { "/" } else { path })).map_err(|e| e.to_string())
+}
+
+/// Returns the response if it was successful, or the error the server gave
+pub async fn check(response: Response) -> Result<Response, String> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response)
+    }
+    Err(match response.json::<ApiError>().await {
+        Ok(error) => error.message,
+        Err(_) => format!("The server responded with {status}"),
+    })
+}
+
+pub async fn get_json<T: DeserializeOwned>(client: &Client, url: Url) -> Result<T, String> {
+    let response = client.get(url).send().await.map_err(|e| e.to_string())?;
+    check(response).await?.json().await.map_err(|e| e.to_string())
+}
+
+pub fn seconds_to_system_time(seconds: f64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs_f64(seconds.max(0.0))
+}
+
+/// Prints how far along a transfer is on the same line, at most every PROGRESS_INTERVAL until it's done
+struct Progress {
+    label: String,
+    total: u64,
+    done: u64,
+    last_printed: Option<Instant>,
+}
+
+impl Progress {
+    fn new(label: String, total: u64, done: u64) -> Self {
+        Self { label, total, done, last_printed: None }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.done += bytes;
+        if self.last_printed.is_none_or(|i| i.elapsed() >= PROGRESS_INTERVAL) {
+            self.print();
+        }
+    }
+
+    fn print(&mut self) {
+        self.last_printed = Some(Instant::now());
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        print!("\r\x1b[2K{} \x1b[96m{percent}%\x1b[0;2m ({} / {})\x1b[0m", self.label, human_readable_size(self.done), human_readable_size(self.total));
+        std::io::stdout().flush().unwrap();
+    }
+
+    fn finish(mut self) {
+        self.print();
+        println!();
+    }
+}
+
+/// Downloads `url` to `path`, setting its modification time to the server's
+///
+/// It's downloaded to a `.part` file next to it first, which is picked up where it left off by the next download if this one doesn't finish.
+/// The part keeps the server's modification time all along, which is how the next download tells it's still part of the same file
+pub async fn download(client: &Client, url: Url, path: &Path, size: u64, modified: f64, label: String) -> Result<(), String> {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".part");
+    let partial_path = PathBuf::from(partial_path);
+
+    let modified_time = seconds_to_system_time(modified);
+    // whole seconds, since not every filesystem keeps anything finer
+    let partial = fs::metadata(&partial_path).ok()
+        .filter(|i| i.len() > 0 && i.len() < size)
+        .filter(|i| i.modified().ok().and_then(|i| i.duration_since(UNIX_EPOCH).ok()).map(|i| i.as_secs()) == Some(modified as u64));
+    let mut offset = 0;
+    let mut response = None;
+    if let Some(partial) = partial {
+        // and the server refuses if the file changed since `modified` was looked up, since the rest wouldn't fit what's already there
+        let resumed = client.get(url.clone())
+            .header(RANGE, format!("bytes={}-", partial.len()))
+            .header(IF_UNMODIFIED_SINCE, DateTime::<Utc>::from(modified_time).format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+            .send().await.map_err(|e| e.to_string())?;
+        match resumed.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                offset = partial.len();
+                response = Some(resumed);
+            },
+            // files inside archives are always sent whole
+            StatusCode::OK => response = Some(resumed),
+            _ => {},
+        }
+    }
+    let mut response = match response {
+        Some(response) => response,
+        None => check(client.get(url).send().await.map_err(|e| e.to_string())?).await?,
+    };
+
+    let mut file = if offset > 0 {
+        tokio::fs::OpenOptions::new().append(true).open(&partial_path).await
+    } else {
+        tokio::fs::File::create(&partial_path).await
+    }.map_err(|e| format!("Couldn't write to {}: {e}", partial_path.display()))?;
+    // writing moves the modification time along, so it's set back after every chunk, in case the download is cut off after it
+    let times = file.try_clone().await.map_err(|e| e.to_string())?.into_std().await;
+    let mut progress = Progress::new(label, size, offset);
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        file.write_all(&chunk).await.map_err(|e| format!("Couldn't write to {}: {e}", partial_path.display()))?;
+        file.flush().await.map_err(|e| e.to_string())?;
+        times.set_modified(modified_time).map_err(|e| e.to_string())?;
+        progress.add(chunk.len() as u64);
+    }
+    progress.finish();
+
+    // the modification time is also how mirror knows it's up to date
+    times.set_modified(modified_time).map_err(|e| e.to_string())?;
+    fs::rename(&partial_path, path).map_err(|e| format!("Couldn't move {} to {}: {e}", partial_path.display(), path.display()))
+}
+
+async fn get(client: &Client, remote: &RemoteUrl, output: Option<PathBuf>) -> Result<(), String> {
+    let (url, base_path) = resolve_url(client, remote).await?;
+    let metadata: RemoteMetadata = get_json(client, api_url(&url, &base_path, "stat")?).await?;
+    if metadata.directory {
+        return Err(format!("{} is a directory, use `gshare3 mirror` to download directories", metadata.name))
+    }
+    let path = output.unwrap_or(PathBuf::from(&metadata.name));
+    let file_url = url.join(&metadata.url).map_err(|e| e.to_string())?;
+    download(client, file_url, &path, metadata.size, metadata.modified, path.display().to_string()).await
+}
+
+async fn ls(client: &Client, remote: &RemoteUrl) -> Result<(), String> {
+    let (url, base_path) = resolve_url(client, remote).await?;
+    let listing: Listing = get_json(client, api_url(&url, &base_path, "list")?).await?;
+    for entry in listing.entries {
+        let modified = DateTime::<Local>::from(seconds_to_system_time(entry.modified)).format("%Y-%m-%d %H:%M");
+        let size = entry.size.map(human_readable_size).unwrap_or(String::from("-"));
+        if entry.directory {
+            println!("{size:>10}  {modified}  \x1b[94m{}/\x1b[0m", entry.name);
+        } else {
+            println!("{size:>10}  {modified}  {}", entry.name);
+        }
+    }
+    Ok(())
+}
+
+async fn mirror(client: &Client, remote: &RemoteUrl, directory: &Path) -> Result<(), String> {
+    let (url, base_path) = resolve_url(client, remote).await?;
+    let files: Vec<RemoteFile> = get_json(client, api_url(&url, &base_path, "manifest")?).await?;
+    let (mut downloaded, mut downloaded_size, mut up_to_date) = (0, 0, 0);
+    for file in files {
+        // the paths come from the server, so they're kept from going anywhere outside the directory
+        if !Path::new(&file.path).components().all(|i| matches!(i, Component::Normal(_))) {
+            eprintln!("\x1b[93mSkipping {}, it isn't a relative path\x1b[0m", file.path);
+            continue;
+        }
+        let path = directory.join(&file.path);
+        if let Ok(metadata) = fs::metadata(&path) {
+            let local_modified = metadata.modified().ok().and_then(|i| i.duration_since(UNIX_EPOCH).ok()).map(|i| i.as_secs());
+            // whole seconds, since not every filesystem keeps anything finer
+            if metadata.len() == file.size && local_modified == Some(file.modified as u64) {
+                up_to_date += 1;
+                continue;
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Couldn't create {}: {e}", parent.display()))?;
+        }
+        let file_url = url.join(&file.url).map_err(|e| e.to_string())?;
+        download(client, file_url, &path, file.size, file.modified, file.path).await?;
+        downloaded += 1;
+        downloaded_size += file.size;
+    }
+    println!("Downloaded \x1b[96m{downloaded}\x1b[0m files ({}), \x1b[96m{up_to_date}\x1b[0m already up to date", human_readable_size(downloaded_size));
+    Ok(())
+}
+
+async fn put(client: &Client, remote: &RemoteUrl, files: &[PathBuf]) -> Result<(), String> {
+    let (url, base_path) = resolve_url(client, remote).await?;
+    let upload_url = api_url(&url, &base_path, "upload")?;
+    for path in files {
+        let name = path.file_name().map(|i| i.to_string_lossy().to_string()).ok_or_else(|| format!("{} isn't a file", path.display()))?;
+        let saved_name = upload(client, upload_url.clone(), path, name.clone()).await?;
+        // the server doesn't overwrite files unless it was started with --upload-overwrite, it picks a new name instead
+        if saved_name != name {
+            println!("\x1b[2m{name} already existed, saved as \x1b[0m{saved_name}");
+        }
+    }
+    Ok(())
+}
+
+/// Uploads the file at `path` to `upload_url` (an `/api/v1/upload` url), showing `label` with the progress, and returns the name the server saved it as
+pub async fn upload(client: &Client, upload_url: Url, path: &Path, label: String) -> Result<String, String> {
+    let name = path.file_name().map(|i| i.to_string_lossy().to_string()).ok_or_else(|| format!("{} isn't a file", path.display()))?;
+    let mut file = tokio::fs::File::open(path).await.map_err(|e| format!("Couldn't open {}: {e}", path.display()))?;
+    let metadata = file.metadata().await.map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err(format!("{} isn't a file, only files can be uploaded", path.display()))
+    }
+
+    // the file is read on another task and sent over as the request body wants it, which is how far the upload has got
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(4);
+    let mut progress = Progress::new(label, metadata.len(), 0);
+    let reader = tokio::spawn(async move {
+        loop {
+            let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+            let length = match file.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(length) => length,
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    break
+                },
+            };
+            chunk.truncate(length);
+            if sender.send(Ok(chunk)).await.is_err() {
+                break
+            }
+            progress.add(length as u64);
+        }
+        progress
+    });
+    let part = Part::stream_with_length(Body::wrap_stream(ReceiverStream::new(receiver)), metadata.len()).file_name(name.clone());
+    let response = client.post(upload_url).multipart(Form::new().part("file", part)).send().await.map_err(|e| e.to_string())?;
+    reader.await.unwrap().finish();
+
+    let saved_names: Vec<String> = check(response).await?.json().await.map_err(|e| e.to_string())?;
+    Ok(saved_names.into_iter().next().unwrap_or(name))
+}
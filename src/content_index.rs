@@ -0,0 +1,301 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs::{self, File}, io::Read, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, RwLock}, thread};
+use rayon::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::{file_watcher::{self, FileWatcher}, get_entry_data, preview, url_encoding, AppState, EntryData};
+
+// bigger files are usually logs or data dumps, which would bloat the index without being worth searching
+const MAX_INDEXED_FILE_SIZE: u64 = 1_000_000;
+// words this long are usually hashes or base64, nobody searches for those
+const MAX_WORD_LENGTH: usize = 64;
+const MAX_LINES_PER_FILE: usize = 5;
+// minified files are one enormous line, so only the part around the match is returned
+const MAX_LINE_LENGTH: usize = 200;
+pub const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug)]
+struct IndexedFile {
+    id: u32,
+    modified: (i64, i64),
+    /// Kept so the file can be taken out of the postings when it changes
+    words: Vec<String>,
+}
+
+/// An inverted index from words to the files they're in
+#[derive(Debug, Default)]
+struct Index {
+    files: HashMap<PathBuf, IndexedFile>,
+    paths: HashMap<u32, PathBuf>,
+    /// Sorted so every word starting with something can be found without going through all of them
+    postings: BTreeMap<String, HashSet<u32>>,
+    next_id: u32,
+}
+
+impl Index {
+    fn insert(&mut self, path: PathBuf, modified: (i64, i64), words: HashSet<String>) {
+        self.remove(&path);
+        let id = self.next_id;
+        self.next_id += 1;
+        for word in &words {
+            self.postings.entry(word.clone()).or_default().insert(id);
+        }
+        self.paths.insert(id, path.clone());
+        self.files.insert(path, IndexedFile { id, modified, words: words.into_iter().collect() });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let Some(file) = self.files.remove(path) else { return };
+        self.paths.remove(&file.id);
+        for word in file.words {
+            if let Some(ids) = self.postings.get_mut(&word) {
+                ids.remove(&file.id);
+                if ids.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// Removes `path` and, if it was a directory, everything that was inside it
+    fn remove_all_inside(&mut self, path: &Path) {
+        let removed_paths = self.files.keys().filter(|i| i.starts_with(path)).cloned().collect::<Vec<_>>();
+        for removed_path in removed_paths {
+            self.remove(&removed_path);
+        }
+    }
+
+    /// Returns the files inside `directory` that have a word starting with each of `words`, sorted by path
+    ///
+    /// Every file inside `directory` is returned if there aren't any words
+    fn candidates(&self, directory: &Path, words: &[String]) -> Vec<PathBuf> {
+        let mut ids: Option<HashSet<u32>> = None;
+        for word in words {
+            let word_ids = self.postings.range(word.clone()..).take_while(|(i, _)| i.starts_with(word.as_str())).flat_map(|(_, i)| i.iter().copied()).collect::<HashSet<_>>();
+            ids = Some(match ids {
+                Some(ids) => ids.intersection(&word_ids).copied().collect(),
+                None => word_ids,
+            });
+        }
+        let mut paths = match ids {
+            Some(ids) => ids.iter().filter_map(|i| self.paths.get(i)).filter(|i| i.starts_with(directory)).cloned().collect::<Vec<_>>(),
+            None => self.files.keys().filter(|i| i.starts_with(directory)).cloned().collect(),
+        };
+        paths.sort_unstable();
+        paths
+    }
+}
+
+#[derive(Debug, Default)]
+struct Indexer {
+    index: RwLock<Index>,
+    indexing: AtomicBool,
+}
+
+/// An index of the words in every text file being served, built by a background thread
+/// and kept up to date when files are uploaded or change on disk
+#[derive(Debug, Clone)]
+pub struct ContentIndex {
+    enabled: bool,
+    indexer: Arc<Indexer>,
+    invalidation_sender: Option<mpsc::Sender<PathBuf>>,
+}
+
+/// A line containing what was searched for
+#[derive(Serialize, Debug, ToSchema)]
+pub struct LineMatch {
+    /// Starts at 1, like in editors
+    number: usize,
+    text: String,
+}
+
+/// A file containing what was searched for, returned by `?grep=`
+#[derive(Serialize, Debug, ToSchema)]
+pub struct FileMatch {
+    #[serde(flatten)]
+    entry: EntryData,
+    /// The first few lines with a match
+    lines: Vec<LineMatch>,
+    /// How many lines have a match, including the ones that weren't returned
+    line_count: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct GrepResults {
+    /// Whether the first pass of the index is still running, in which case files could be missing from the results
+    indexing: bool,
+    files: Vec<FileMatch>,
+}
+
+impl ContentIndex {
+    /// Starts indexing `roots` in the background, unless `enabled` is false
+    pub fn new(roots: Vec<PathBuf>, enabled: bool, file_watcher: FileWatcher) -> Self {
+        let indexer = Arc::new(Indexer::default());
+        if !enabled {
+            return Self { enabled, indexer, invalidation_sender: None }
+        }
+
+        let (invalidation_sender, invalidation_receiver) = mpsc::channel();
+        let thread_indexer = indexer.clone();
+        let watcher_sender = invalidation_sender.clone();
+        indexer.indexing.store(true, Ordering::Relaxed);
+        // reading every file takes a long time, so the website works (with partial results) while it's going
+        thread::spawn(move || {
+            for root in &roots {
+                thread_indexer.update(root);
+            }
+            thread_indexer.indexing.store(false, Ordering::Relaxed);
+            file_watcher.subscribe(watcher_sender);
+            while let Some(changed_paths) = file_watcher::receive_changes(&invalidation_receiver) {
+                for changed_path in changed_paths.into_iter().collect::<HashSet<_>>() {
+                    thread_indexer.update(&changed_path);
+                }
+            }
+        });
+
+        Self { enabled, indexer, invalidation_sender: Some(invalidation_sender) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Marks `path` (a file, or a directory and everything inside it) as needing to be indexed again
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) {
+        if let Some(sender) = &self.invalidation_sender {
+            let _ = sender.send(path.as_ref().to_path_buf());
+        }
+    }
+
+    /// Returns up to `limit` files inside `directory` containing `query` (ignoring case), and the lines it's on, reading the files
+    /// that might match
+    fn search(&self, directory: &Path, query: &str, limit: usize) -> Vec<(PathBuf, Vec<LineMatch>, usize)> {
+        let query = query.to_lowercase();
+        // the candidates are the files with a word starting with each word of the query, which is all the index can tell. That's looser
+        // than the query being in there, so the files are read to check it is (in that order, as a substring). But it's also stricter
+        // about the first word, which isn't found in the middle of a longer one: `ello world` doesn't find `hello world`
+        let candidates = self.indexer.index.read().unwrap().candidates(directory, &words(&query).collect::<Vec<_>>());
+        let mut results = Vec::new();
+        for path in candidates {
+            if results.len() >= limit {
+                break
+            }
+            let Some(text) = read_text(&path) else { continue };
+            let mut lines = Vec::new();
+            let mut line_count = 0;
+            for (index, line) in text.lines().enumerate() {
+                let lowercase_line = line.to_lowercase();
+                let Some(match_start) = lowercase_line.find(&query) else { continue };
+                line_count += 1;
+                if lines.len() < MAX_LINES_PER_FILE {
+                    lines.push(LineMatch { number: index + 1, text: snippet(line, lowercase_line[..match_start].chars().count()) });
+                }
+            }
+            if line_count > 0 {
+                results.push((path, lines, line_count));
+            }
+        }
+        results
+    }
+}
+
+impl Indexer {
+    /// Brings whatever's at `path` up to date in the index
+    fn update(&self, path: &Path) {
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => self.update_directory(path),
+            Ok(metadata) if metadata.is_file() => self.update_file(path, &metadata),
+            // removed, or replaced with a symlink (which aren't followed anywhere else either)
+            _ => self.index.write().unwrap().remove_all_inside(path),
+        }
+    }
+
+    fn update_directory(&self, directory: &Path) {
+        let mut files = Vec::new();
+        let mut directories = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let Ok(read_dir) = fs::read_dir(&directory) else { continue };
+            for entry in read_dir.flatten() {
+                let Ok(file_type) = entry.file_type() else { continue };
+                if file_type.is_dir() {
+                    directories.push(entry.path());
+                } else if file_type.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        }
+        // anything that was inside the directory but wasn't found now has been removed
+        {
+            let found = files.iter().collect::<HashSet<_>>();
+            let mut index = self.index.write().unwrap();
+            let removed_paths = index.files.keys().filter(|i| i.starts_with(directory) && !found.contains(i)).cloned().collect::<Vec<_>>();
+            for removed_path in removed_paths {
+                index.remove(&removed_path);
+            }
+        }
+        files.par_iter().for_each(|path| {
+            if let Ok(metadata) = path.symlink_metadata() {
+                self.update_file(path, &metadata);
+            }
+        });
+    }
+
+    fn update_file(&self, path: &Path, metadata: &fs::Metadata) {
+        let modified = (metadata.mtime(), metadata.mtime_nsec());
+        if self.index.read().unwrap().files.get(path).is_some_and(|i| i.modified == modified) {
+            return
+        }
+        // a file that isn't text anymore (or got too big) shouldn't stay in the index with its old contents
+        let words = (metadata.len() <= MAX_INDEXED_FILE_SIZE).then(|| read_text(path)).flatten().map(|text| words(&text.to_lowercase()).collect::<HashSet<_>>());
+        let mut index = self.index.write().unwrap();
+        match words {
+            Some(words) => index.insert(path.to_path_buf(), modified, words),
+            None => index.remove(path),
+        }
+    }
+}
+
+/// Returns the words in already lowercased text, a word being anything between characters that aren't letters or numbers
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|i: char| !i.is_alphanumeric()).filter(|i| !i.is_empty() && i.len() <= MAX_WORD_LENGTH).map(String::from)
+}
+
+/// Returns the contents of the file if it's a text file that's small enough to be indexed
+fn read_text(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    File::open(path).ok()?.take(MAX_INDEXED_FILE_SIZE).read_to_end(&mut bytes).ok()?;
+    preview::as_text(&bytes).map(String::from)
+}
+
+/// Returns the line, cut down to the part around the match if it's too long
+fn snippet(line: &str, match_start: usize) -> String {
+    let line = line.trim_end();
+    if line.chars().count() <= MAX_LINE_LENGTH {
+        return line.to_string()
+    }
+    let start = match_start.saturating_sub(MAX_LINE_LENGTH / 4);
+    line.chars().skip(start).take(MAX_LINE_LENGTH).collect()
+}
+
+/// Searches the contents of the files inside `roots`, given as paths and their already encoded urls
+///
+/// A file matches if it contains `query` (ignoring case) starting at the beginning of a word, see ContentIndex::search
+pub fn grep(state: &AppState, roots: Vec<(PathBuf, String)>, query: &str, limit: usize) -> GrepResults {
+    let mut files = Vec::new();
+    for (root, root_url) in roots {
+        for (path, lines, line_count) in state.content_index.search(&root, query, limit - files.len()) {
+            let relative_path = path.strip_prefix(&root).unwrap();
+            let url = if relative_path.as_os_str().is_empty() {
+                root_url.clone()
+            } else {
+                root_url.clone() + &relative_path.iter().map(url_encoding::encode_name).collect::<Vec<_>>().join("/")
+            };
+            if let Some(entry) = get_entry_data(state, &path, |_, _| url) {
+                files.push(FileMatch { entry, lines, line_count });
+            }
+        }
+        if files.len() >= limit {
+            break
+        }
+    }
+    GrepResults { indexing: state.content_index.indexer.indexing.load(Ordering::Relaxed), files }
+}
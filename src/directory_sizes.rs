@@ -0,0 +1,356 @@
+use std::{collections::{HashMap, HashSet}, fs, os::unix::{ffi::OsStrExt, fs::MetadataExt}, path::{Path, PathBuf}, sync::{mpsc, Arc, Mutex}, thread, time::{Duration, Instant}};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::file_watcher::{self, FileWatcher};
+// writing the cache is slow for big trees, so changes are only saved this often
+const CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What's found when reading a single directory, not including its subdirectories' contents
+///
+/// This is what gets saved to the cache file, since it mostly stays valid for as long as the directory's mtime doesn't change. Files
+/// being rewritten in place don't change it though, so cached scans are checked in the background once the sizes from them are shown
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ScannedDirectory {
+    modified: (i64, i64),
+    /// Combined size of the files in the directory that only have one link
+    files_size: u64,
+    /// Files in the directory with multiple links, as (device, inode, size)
+    hard_links: Vec<(u64, u64, u64)>,
+    subdirectories: Vec<PathBuf>,
+}
+
+/// The size of everything inside a directory
+///
+/// Files with multiple links are kept separate so each one is only counted once,
+/// even if it appears in several subdirectories
+#[derive(Debug, Clone, Default)]
+struct DirectoryTotal {
+    single_links_size: u64,
+    hard_links: Arc<HashMap<(u64, u64), u64>>,
+}
+
+impl DirectoryTotal {
+    fn size(&self) -> u64 {
+        self.single_links_size + self.hard_links.values().sum::<u64>()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Scanner {
+    totals: Mutex<HashMap<PathBuf, DirectoryTotal>>,
+    scans: Mutex<HashMap<PathBuf, ScannedDirectory>>,
+    /// Directories that couldn't be read, which don't have a size until they change
+    failed: Mutex<HashSet<PathBuf>>,
+}
+
+/// Recursive directory sizes, computed by a pool of background threads and kept up to
+/// date when files are uploaded or change on disk
+#[derive(Debug, Clone)]
+pub struct DirectorySizes {
+    enabled: bool,
+    scanner: Arc<Scanner>,
+    invalidation_sender: Option<mpsc::Sender<PathBuf>>,
+}
+
+impl DirectorySizes {
+    /// Starts computing the sizes of `roots` in the background, unless `enabled` is false
+    ///
+    /// `threads` directories are read at once, and if there's a `cache_file`
+    /// directories that haven't been modified since it was written aren't read again
+    pub fn new(roots: Vec<PathBuf>, enabled: bool, threads: usize, cache_file: Option<PathBuf>, file_watcher: FileWatcher) -> Self {
+        let scanner = Arc::new(Scanner::default());
+        if !enabled {
+            return Self { enabled, scanner, invalidation_sender: None }
+        }
+
+        let cached_scans = cache_file.as_deref().map(load_cache).unwrap_or_default();
+        *scanner.scans.lock().unwrap() = cached_scans.clone();
+
+        let (invalidation_sender, invalidation_receiver) = mpsc::channel();
+        let thread_scanner = scanner.clone();
+        let watcher_sender = invalidation_sender.clone();
+        // get directory sizes in separate threads so you don't have to wait before accessing the website
+        thread::spawn(move || {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).thread_name(|i| format!("directory-sizes-{i}")).build().unwrap();
+            pool.install(|| roots.par_iter().for_each(|root| { thread_scanner.total(root); }));
+            // anything that wasn't visited doesn't exist anymore, so there's no point saving it
+            let totals = thread_scanner.totals.lock().unwrap();
+            thread_scanner.scans.lock().unwrap().retain(|path, _| totals.contains_key(path));
+            drop(totals);
+            if let Some(cache_file) = &cache_file {
+                save_cache(cache_file, &thread_scanner.scans.lock().unwrap());
+            }
+            // subscribed after the first pass so events don't pile up while it's running
+            file_watcher.subscribe(watcher_sender);
+            if !cached_scans.is_empty() {
+                check_cached_scans(&roots, &thread_scanner, &pool, cached_scans);
+                if let Some(cache_file) = &cache_file {
+                    save_cache(cache_file, &thread_scanner.scans.lock().unwrap());
+                }
+            }
+            recompute_invalidated_sizes(&roots, &thread_scanner, &pool, cache_file.as_deref(), invalidation_receiver);
+        });
+
+        Self { enabled, scanner, invalidation_sender: Some(invalidation_sender) }
+    }
+
+    /// Returns the size of the directory if it's been computed
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        self.scanner.totals.lock().unwrap().get(path.as_ref()).map(DirectoryTotal::size)
+    }
+
+    /// Returns whether the size of the directory is still being (re)computed,
+    /// as opposed to not being available at all (because it's disabled, or the directory can't be read)
+    pub fn is_computing<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.enabled && self.get(&path).is_none() && !self.scanner.failed.lock().unwrap().contains(path.as_ref())
+    }
+
+    /// Marks the size of `path` (and therefore all of its ancestors) as outdated
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) {
+        if let Some(sender) = &self.invalidation_sender {
+            // the receiver only goes away if the thread panicked, in which case there's nothing to update anyway
+            let _ = sender.send(path.as_ref().to_path_buf());
+        }
+    }
+}
+
+impl Scanner {
+    /// Returns the total of a directory, reading it (and its subdirectories, in parallel) if it isn't known already
+    fn total(&self, path: &Path) -> DirectoryTotal {
+        if let Some(total) = self.totals.lock().unwrap().get(path) {
+            return total.clone()
+        }
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) if !metadata.is_symlink() => metadata,
+            // otherwise it'd be shown as computing forever
+            _ => {
+                self.failed.lock().unwrap().insert(path.to_path_buf());
+                return DirectoryTotal::default()
+            }
+        };
+        // only happens for the top-level paths, files in directories are handled by scan
+        if metadata.is_file() {
+            return DirectoryTotal { single_links_size: metadata.size(), ..Default::default() }
+        }
+
+        let modified = (metadata.mtime(), metadata.mtime_nsec());
+        let cached_scan = self.scans.lock().unwrap().get(path).filter(|i| i.modified == modified).cloned();
+        let scan = match cached_scan {
+            Some(scan) => scan,
+            None => {
+                let Some(scan) = scan(path, modified) else {
+                    self.failed.lock().unwrap().insert(path.to_path_buf());
+                    return DirectoryTotal::default()
+                };
+                self.scans.lock().unwrap().insert(path.to_path_buf(), scan.clone());
+                scan
+            }
+        };
+
+        let subdirectory_totals = scan.subdirectories.par_iter().map(|i| self.total(&path.join(i))).collect::<Vec<_>>();
+
+        let single_links_size = scan.files_size + subdirectory_totals.iter().map(|i| i.single_links_size).sum::<u64>();
+        let mut with_hard_links = subdirectory_totals.iter().filter(|i| !i.hard_links.is_empty()).collect::<Vec<_>>();
+        let hard_links = if scan.hard_links.is_empty() && with_hard_links.len() <= 1 {
+            // nothing to merge, so the directory shares the map of the only subdirectory with hard links (or the empty one), which is most
+            // of them: hard links are only copied into a new map in the directories where ones from different places meet
+            with_hard_links.pop().map(|i| i.hard_links.clone()).unwrap_or_default()
+        } else {
+            let mut hard_links = HashMap::with_capacity(scan.hard_links.len() + with_hard_links.iter().map(|i| i.hard_links.len()).sum::<usize>());
+            hard_links.extend(scan.hard_links.iter().map(|(device, inode, size)| ((*device, *inode), *size)));
+            for subdirectory_total in with_hard_links {
+                hard_links.extend(subdirectory_total.hard_links.iter());
+            }
+            Arc::new(hard_links)
+        };
+        let total = DirectoryTotal { single_links_size, hard_links };
+
+        self.totals.lock().unwrap().insert(path.to_path_buf(), total.clone());
+        total
+    }
+}
+
+/// Reads a single directory, returns None if it can't be read
+fn scan(path: &Path, modified: (i64, i64)) -> Option<ScannedDirectory> {
+    let read_dir = fs::read_dir(path).ok()?;
+    let mut scan = ScannedDirectory { modified, files_size: 0, hard_links: Vec::new(), subdirectories: Vec::new() };
+    // entries can disappear while this is running, those are just skipped
+    for entry in read_dir.flatten() {
+        // I'm guessing file_type can fail for symlinks?
+        let Ok(file_type) = entry.file_type() else { continue };
+        if file_type.is_dir() {
+            scan.subdirectories.push(PathBuf::from(entry.file_name()));
+        } else if file_type.is_file() {
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.nlink() > 1 {
+                scan.hard_links.push((metadata.dev(), metadata.ino(), metadata.size()));
+            } else {
+                scan.files_size += metadata.size();
+            }
+        }
+    }
+    Some(scan)
+}
+
+/// Reads the directories whose scans came from the cache again, and recomputes the sizes of the ones that turned out different
+fn check_cached_scans(roots: &[PathBuf], scanner: &Scanner, pool: &rayon::ThreadPool, cached_scans: HashMap<PathBuf, ScannedDirectory>) {
+    // the ones that weren't used (because the directory's mtime changed, or it's gone) were already read again or don't matter
+    let used_scans = scanner.scans.lock().unwrap().iter()
+        .filter(|(path, scan)| cached_scans.get(*path) == Some(scan))
+        .map(|(path, scan)| (path.clone(), scan.clone()))
+        .collect::<Vec<_>>();
+    drop(cached_scans);
+    let changed_scans = pool.install(|| used_scans.into_par_iter()
+        .filter_map(|(path, cached_scan)| scan(&path, cached_scan.modified).filter(|i| *i != cached_scan).map(|i| (path, i)))
+        .collect::<Vec<_>>());
+    if changed_scans.is_empty() {
+        return
+    }
+    {
+        let mut totals = scanner.totals.lock().unwrap();
+        let mut scans = scanner.scans.lock().unwrap();
+        for (path, scan) in changed_scans {
+            forget_total(roots, &mut totals, &path);
+            scans.insert(path, scan);
+        }
+    }
+    pool.install(|| roots.par_iter().for_each(|root| { scanner.total(root); }));
+}
+
+/// Forgets the total of `path` and its ancestors, which include it
+fn forget_total(roots: &[PathBuf], totals: &mut HashMap<PathBuf, DirectoryTotal>, path: &Path) {
+    for root in roots.iter().filter(|i| path.starts_with(i)) {
+        for ancestor in path.ancestors().take_while(|i| i.starts_with(root)) {
+            totals.remove(ancestor);
+        }
+    }
+}
+
+/// Waits for changed paths, forgets the sizes of them and their ancestors, and recomputes them
+fn recompute_invalidated_sizes(roots: &[PathBuf], scanner: &Scanner, pool: &rayon::ThreadPool, cache_file: Option<&Path>, invalidation_receiver: mpsc::Receiver<PathBuf>) {
+    let mut last_save = Instant::now();
+    while let Some(changed_paths) = file_watcher::receive_changes(&invalidation_receiver) {
+        forget_changed_paths(roots, scanner, &changed_paths);
+
+        // unchanged subdirectories are still cached, so this only rescans the invalidated ones
+        pool.install(|| roots.par_iter().for_each(|root| { scanner.total(root); }));
+
+        if let Some(cache_file) = cache_file {
+            if last_save.elapsed() >= CACHE_SAVE_INTERVAL {
+                save_cache(cache_file, &scanner.scans.lock().unwrap());
+                last_save = Instant::now();
+            }
+        }
+    }
+}
+
+/// Forgets the scans and totals of the changed paths and their ancestors
+///
+/// Anything inside them is kept: if a changed path was a directory that was removed or renamed its parent is read again and
+/// doesn't list it anymore, and the scans of the ones that are still there are only used if their mtime didn't change
+fn forget_changed_paths(roots: &[PathBuf], scanner: &Scanner, changed_paths: &[PathBuf]) {
+    let mut totals = scanner.totals.lock().unwrap();
+    let mut scans = scanner.scans.lock().unwrap();
+    let mut failed = scanner.failed.lock().unwrap();
+    for changed_path in changed_paths {
+        scans.remove(changed_path);
+        // like one that can be read now that its permissions changed
+        failed.retain(|i| !i.starts_with(changed_path));
+        // a file changing size doesn't change its directory's mtime, so the directory has to be read again
+        if let Some(parent) = changed_path.parent() {
+            scans.remove(parent);
+        }
+        forget_total(roots, &mut totals, changed_path);
+    }
+}
+
+/// Returns the default location of the cache file for `roots`, if the platform has a cache directory
+///
+/// Each set of roots gets its own file, otherwise servers sharing different directories would remove each other's scans every time
+/// they save theirs
+pub fn default_cache_file(roots: &[PathBuf]) -> Option<PathBuf> {
+    let mut hasher = Sha256::new();
+    for root in roots {
+        hasher.update(root.canonicalize().as_deref().unwrap_or(root).as_os_str().as_bytes());
+        hasher.update([0]);
+    }
+    let roots_hash = hasher.finalize().iter().take(8).map(|i| format!("{i:02x}")).collect::<String>();
+    dirs::cache_dir().map(|i| i.join("gshare3").join(format!("directory-sizes-{roots_hash}.json")))
+}
+
+fn load_cache(cache_file: &Path) -> HashMap<PathBuf, ScannedDirectory> {
+    // a missing or broken cache just means everything gets scanned
+    fs::read(cache_file).ok().and_then(|i| serde_json::from_slice(&i).ok()).unwrap_or_default()
+}
+
+fn save_cache(cache_file: &Path, scans: &HashMap<PathBuf, ScannedDirectory>) {
+    // paths that aren't valid UTF-8 can't be put in JSON, so those directories are just scanned again next time
+    let scans = scans.iter()
+        .filter(|(path, scan)| path.to_str().is_some() && scan.subdirectories.iter().all(|i| i.to_str().is_some()))
+        .collect::<HashMap<_, _>>();
+    let result = (|| {
+        if let Some(parent) = cache_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // written to a separate file first so a crash halfway through doesn't leave a broken cache, and the pid keeps
+        // servers using the same cache file from writing to the same temporary one
+        let temporary_file = cache_file.with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&temporary_file, serde_json::to_vec(&scans)?)?;
+        fs::rename(&temporary_file, cache_file)
+    })();
+    if let Err(e) = result {
+        eprintln!("\x1b[91mCouldn't save directory sizes to {}: {e}\x1b[0m", cache_file.to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temporary_directory::TemporaryDirectory;
+    use super::*;
+
+    #[test]
+    fn uploads_only_rescan_the_directory_and_its_ancestors() {
+        let directory = TemporaryDirectory::new("directory-sizes");
+        let root = directory.to_path_buf();
+        for (path, contents) in [("uploads/child/a.txt", "aaaa"), ("sibling/b.txt", "bb"), ("c.txt", "c")] {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), contents).unwrap();
+        }
+        let roots = [root.clone()];
+        let scanner = Scanner::default();
+        assert_eq!(scanner.total(&root).size(), 7);
+
+        // if the sibling's and child's totals weren't kept these would be read again, and the sizes put here wouldn't be used
+        for (path, size) in [("uploads/child", 100), ("sibling", 1000)] {
+            scanner.totals.lock().unwrap().insert(root.join(path), DirectoryTotal { single_links_size: size, ..Default::default() });
+        }
+        fs::write(root.join("uploads").join("uploaded.txt"), "uploaded").unwrap();
+        forget_changed_paths(&roots, &scanner, &[root.join("uploads")]);
+        {
+            let scans = scanner.scans.lock().unwrap();
+            assert!(scans.contains_key(&root.join("uploads/child")));
+            assert!(scans.contains_key(&root.join("sibling")));
+            assert!(!scans.contains_key(&root.join("uploads")));
+        }
+        assert_eq!(scanner.total(&root.join("uploads")).size(), 108);
+        assert_eq!(scanner.total(&root).size(), 1109);
+    }
+
+    #[test]
+    fn paths_that_cant_be_read_arent_computing() {
+        let sizes = DirectorySizes { enabled: true, scanner: Arc::new(Scanner::default()), invalidation_sender: None };
+        let directory = TemporaryDirectory::new("directory-sizes");
+        let missing = directory.join("missing");
+        sizes.scanner.total(&missing);
+        assert!(!sizes.is_computing(&missing));
+    }
+
+    #[test]
+    fn different_roots_get_different_cache_files() {
+        let Some(cache_file) = default_cache_file(&[PathBuf::from("/a")]) else { return };
+        assert_eq!(default_cache_file(&[PathBuf::from("/a")]), Some(cache_file.clone()));
+        assert_ne!(default_cache_file(&[PathBuf::from("/b")]), Some(cache_file.clone()));
+        assert_ne!(default_cache_file(&[PathBuf::from("/a"), PathBuf::from("/b")]), Some(cache_file));
+    }
+}
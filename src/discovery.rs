@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, net::IpAddr, time::{Duration, Instant}};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+/// Browsers and other tools find any web server under this one
+const HTTP_SERVICE_TYPE: &str = "_http._tcp.local.";
+/// `gshare3 discover` looks for this one, which has the title and upload mode in its TXT record
+const GSHARE3_SERVICE_TYPE: &str = "_gshare3._tcp.local.";
+
+/// What a server tells others about itself
+pub struct Advertisement {
+    pub title: Option<String>,
+    /// `none`, `new` or `overwrite`
+    pub upload: &'static str,
+    pub port: u16,
+    /// Where the server is on the host, which is only something other than `/` with a base path
+    pub path: String,
+    /// Private servers are only advertised on loopback, since nothing else can reach them anyway
+    pub private: bool,
+}
+
+/// Advertises the server with mDNS until the returned daemon is dropped, or prints why it can't
+pub fn advertise(advertisement: Advertisement) -> Option<ServiceDaemon> {
+    let result = (|| {
+        let daemon = ServiceDaemon::new()?;
+        // loopback is off by default, but it's what lets `gshare3 discover` find servers on the same machine
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+        if advertisement.private {
+            daemon.disable_interface(IfKind::All)?;
+            daemon.enable_interface(IfKind::LoopbackV4)?;
+        }
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        // instance names have to be unique on the network, and there can be several servers on one machine
+        let instance_name = format!("{} on {hostname} ({})", advertisement.title.as_deref().unwrap_or("gshare3"), advertisement.port);
+        // addresses belong to host names in mDNS, so a private server gets one of its own to keep from picking up the addresses of public ones
+        let host = if advertisement.private { format!("{hostname}-private.local.") } else { format!("{hostname}.local.") };
+        let properties = [("title", advertisement.title.clone().unwrap_or_default()), ("upload", advertisement.upload.to_string()), ("path", advertisement.path.clone())];
+        for service_type in [HTTP_SERVICE_TYPE, GSHARE3_SERVICE_TYPE] {
+            let service = if advertisement.private {
+                ServiceInfo::new(service_type, &instance_name, &host, "127.0.0.1", advertisement.port, &properties[..])?
+            } else {
+                // without addresses the daemon fills in (and keeps up to date) the ones of every interface it's on
+                ServiceInfo::new(service_type, &instance_name, &host, (), advertisement.port, &properties[..])?.enable_addr_auto()
+            };
+            daemon.register(service)?;
+        }
+        Ok::<_, mdns_sd::Error>(daemon)
+    })();
+    match result {
+        Ok(daemon) => Some(daemon),
+        Err(e) => {
+            eprintln!("\x1b[93mCouldn't advertise the server on the local network: {e}\x1b[0m");
+            None
+        },
+    }
+}
+
+struct DiscoveredServer {
+    title: String,
+    upload: String,
+    addresses: Vec<IpAddr>,
+    port: u16,
+    path: String,
+}
+
+/// Prints the gshare3 servers found on the local network within `timeout`
+pub fn discover(timeout: Duration) -> Result<(), String> {
+    let daemon = ServiceDaemon::new().map_err(|e| format!("Couldn't start mDNS: {e}"))?;
+    daemon.enable_interface(IfKind::LoopbackV4).map_err(|e| format!("Couldn't start mDNS: {e}"))?;
+    let receiver = daemon.browse(GSHARE3_SERVICE_TYPE).map_err(|e| format!("Couldn't start mDNS: {e}"))?;
+    let mut servers = BTreeMap::new();
+    let deadline = Instant::now() + timeout;
+    while let Ok(event) = receiver.recv_deadline(deadline) {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let mut addresses = info.get_addresses().iter().copied().collect::<Vec<_>>();
+                // the addresses other people can reach go first
+                addresses.sort_unstable_by_key(|i| (i.is_loopback(), i.is_ipv6(), *i));
+                servers.insert(info.get_fullname().to_string(), DiscoveredServer {
+                    title: info.get_property_val_str("title").filter(|i| !i.is_empty()).unwrap_or("gshare3").to_string(),
+                    upload: info.get_property_val_str("upload").unwrap_or("none").to_string(),
+                    addresses,
+                    port: info.get_port(),
+                    path: info.get_property_val_str("path").filter(|i| *i != "/").unwrap_or_default().to_string(),
+                });
+            },
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            },
+            _ => {},
+        }
+    }
+    let _ = daemon.shutdown();
+    if servers.is_empty() {
+        println!("\x1b[2mNo servers found\x1b[0m");
+        return Ok(())
+    }
+    for server in servers.values() {
+        let urls = server.addresses.iter().map(|i| match i {
+            IpAddr::V4(ip) => format!("{ip}:{}{}", server.port, server.path),
+            IpAddr::V6(ip) => format!("[{ip}]:{}{}", server.port, server.path),
+        }).collect::<Vec<_>>();
+        let upload = match server.upload.as_str() {
+            "new" => " \x1b[95mupload \x1b[92m(new files only)\x1b[0m",
+            "overwrite" => " \x1b[91mupload+overwrite\x1b[0m",
+            _ => "",
+        };
+        println!("{}{upload} \x1b[96m{}\x1b[0m", server.title, urls.join(" "));
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::{collections::BTreeMap, fs::{self, File}, io::{self, Read}, os::unix::fs::MetadataExt, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha512};
+use utoipa::ToSchema;
+use crate::archive::ArchiveEntry;
+
+// enough to tell text from binary, same as what previews look at
+const MIME_SNIFF_BYTES: u64 = 8_000;
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+/// Parses the comma-separated `?hash=` list, returning the name that isn't a known algorithm if there is one
+pub fn parse_hash_algorithms(list: &str) -> Result<Vec<HashAlgorithm>, String> {
+    let mut algorithms = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let algorithm = match name.to_lowercase().as_str() {
+            "sha256" => HashAlgorithm::Sha256,
+            "sha512" => HashAlgorithm::Sha512,
+            _ => return Err(name.to_string()),
+        };
+        if !algorithms.contains(&algorithm) {
+            algorithms.push(algorithm);
+        }
+    }
+    Ok(algorithms)
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Owner {
+    id: u32,
+    /// None if there's no user or group with the id on this machine
+    name: Option<String>,
+}
+
+/// What `?data=true` returns for a file, and `/api/v1/stat` for anything
+#[derive(Serialize, Debug, ToSchema)]
+pub struct FileMetadata {
+    name: String,
+    url: String,
+    directory: bool,
+    size: u64,
+    /// Seconds since the unix epoch, like everything else here
+    modified: f64,
+    /// When the contents or the metadata (like permissions) last changed, None inside archives
+    changed: Option<f64>,
+    /// None if the filesystem doesn't keep track
+    created: Option<f64>,
+    /// None inside archives
+    accessed: Option<f64>,
+    /// None for directories
+    mime_type: Option<String>,
+    /// Like `ls -l` shows them, e.g. `-rw-r--r--`, None inside archives
+    permissions: Option<String>,
+    /// The permission bits, e.g. 420 for 0o644, None inside archives
+    mode: Option<u32>,
+    /// None inside archives
+    owner: Option<Owner>,
+    /// None inside archives
+    group: Option<Owner>,
+    /// Where the path points, if it's a symlink (the rest is about the file it points to)
+    symlink_target: Option<String>,
+    /// The hashes asked for with `?hash=sha256,sha512`, as lowercase hex, always empty for directories and inside archives
+    hashes: BTreeMap<&'static str, String>,
+}
+
+/// Returns the metadata of a file or directory, where `url` is its (already encoded) url
+///
+/// Hashing reads the whole file, so that's only done for the algorithms in `hash_algorithms`
+pub fn file_metadata(path: &Path, url: String, hash_algorithms: &[HashAlgorithm]) -> io::Result<FileMetadata> {
+    let metadata = path.metadata()?;
+    let symlink_target = if path.symlink_metadata()?.is_symlink() { Some(fs::read_link(path)?.to_string_lossy().to_string()) } else { None };
+    let directory = metadata.is_dir();
+    Ok(FileMetadata {
+        name: path.file_name().map(|i| i.to_string_lossy().to_string()).unwrap_or_default(),
+        url,
+        directory,
+        size: metadata.len(),
+        modified: metadata.mtime() as f64 + metadata.mtime_nsec() as f64 / 1e9,
+        changed: Some(metadata.ctime() as f64 + metadata.ctime_nsec() as f64 / 1e9),
+        created: metadata.created().ok().map(seconds_since_epoch),
+        accessed: Some(metadata.atime() as f64 + metadata.atime_nsec() as f64 / 1e9),
+        mime_type: if directory { None } else { Some(detect_mime_type(path)) },
+        permissions: Some(permissions_string(metadata.mode())),
+        mode: Some(metadata.mode() & 0o7777),
+        owner: Some(Owner { id: metadata.uid(), name: uzers::get_user_by_uid(metadata.uid()).map(|i| i.name().to_string_lossy().to_string()) }),
+        group: Some(Owner { id: metadata.gid(), name: uzers::get_group_by_gid(metadata.gid()).map(|i| i.name().to_string_lossy().to_string()) }),
+        symlink_target,
+        hashes: if directory { BTreeMap::new() } else { hashes(path, hash_algorithms)? },
+    })
+}
+
+/// Returns the metadata of a file or directory inside an archive, which is just what the archive keeps track of
+pub fn archive_entry_metadata(entry: &ArchiveEntry, url: String) -> FileMetadata {
+    FileMetadata {
+        name: entry.name().to_string(),
+        url,
+        directory: entry.directory,
+        size: entry.size,
+        modified: entry.modified as f64,
+        changed: None,
+        created: None,
+        accessed: None,
+        mime_type: if entry.directory { None } else { Some(mime_guess::from_path(entry.name()).first_or_octet_stream().to_string()) },
+        permissions: None,
+        mode: None,
+        owner: None,
+        group: None,
+        symlink_target: None,
+        hashes: BTreeMap::new(),
+    }
+}
+
+/// Times from before the epoch (which files extracted from old archives or from cameras with a wrong clock can have) are the epoch
+pub fn seconds_since_epoch(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|i| i.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Goes by the extension, and looks at the contents of files without a known one to tell text from binary
+fn detect_mime_type(path: &Path) -> String {
+    if let Some(mime_type) = mime_guess::from_path(path).first_raw() {
+        return mime_type.to_string()
+    }
+    let mut bytes = Vec::new();
+    let is_text = File::open(path).and_then(|i| i.take(MIME_SNIFF_BYTES).read_to_end(&mut bytes)).is_ok() && crate::preview::as_text(&bytes).is_some();
+    String::from(if is_text { "text/plain" } else { "application/octet-stream" })
+}
+
+/// Returns the permissions like `ls -l` shows them, including setuid, setgid and sticky bits
+fn permissions_string(mode: u32) -> String {
+    let file_type = match mode & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        _ => '-',
+    };
+    let mut permissions = String::from(file_type);
+    for (shift, special_bit, special_character) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        permissions.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        permissions.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        permissions.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => special_character,
+            // the special bit without execute is shown in uppercase
+            (false, true) => special_character.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    permissions
+}
+
+/// Hashes the file with every algorithm in one read, returning them by name as lowercase hex
+pub fn hashes(path: &Path, algorithms: &[HashAlgorithm]) -> io::Result<BTreeMap<&'static str, String>> {
+    if algorithms.is_empty() {
+        return Ok(BTreeMap::new())
+    }
+    let mut sha256 = algorithms.contains(&HashAlgorithm::Sha256).then(Sha256::new);
+    let mut sha512 = algorithms.contains(&HashAlgorithm::Sha512).then(Sha512::new);
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    loop {
+        let length = file.read(&mut buffer)?;
+        if length == 0 {
+            break
+        }
+        if let Some(sha256) = &mut sha256 {
+            sha256.update(&buffer[..length]);
+        }
+        if let Some(sha512) = &mut sha512 {
+            sha512.update(&buffer[..length]);
+        }
+    }
+    let mut hashes = BTreeMap::new();
+    if let Some(sha256) = sha256 {
+        hashes.insert(HashAlgorithm::Sha256.name(), sha256.finalize().iter().map(|i| format!("{i:02x}")).collect());
+    }
+    if let Some(sha512) = sha512 {
+        hashes.insert(HashAlgorithm::Sha512.name(), sha512.finalize().iter().map(|i| format!("{i:02x}")).collect());
+    }
+    Ok(hashes)
+}
@@ -0,0 +1,54 @@
+use std::{path::PathBuf, sync::{mpsc, Arc, Mutex, OnceLock}, thread, time::Duration};
+use notify::{RecursiveMode, Watcher};
+
+// how long to wait for more changes before handling them, so that copying a
+// folder full of files isn't handled once per file
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Tells everything kept up to date with the served files (directory sizes and the content index) when they change
+///
+/// There's only one watcher for all of them, since watching a directory recursively takes an inotify watch for every directory
+/// inside, and there's a limit to those
+#[derive(Clone)]
+pub struct FileWatcher {
+    roots: Arc<Vec<PathBuf>>,
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<PathBuf>>>>,
+    /// Started by the first subscriber, and it stops watching when the last clone is dropped
+    watcher: Arc<OnceLock<Option<notify::RecommendedWatcher>>>,
+}
+
+impl FileWatcher {
+    /// Doesn't start watching `roots` until something subscribes, so events don't pile up while nothing is listening
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        Self { roots: Arc::new(roots), subscribers: Arc::default(), watcher: Arc::default() }
+    }
+
+    /// Sends every path that changes inside the roots to `sender` from now on
+    pub fn subscribe(&self, sender: mpsc::Sender<PathBuf>) {
+        self.subscribers.lock().unwrap().push(sender);
+        self.watcher.get_or_init(|| watch_roots(&self.roots, self.subscribers.clone()));
+    }
+}
+
+/// Forwards every path the filesystem watcher reports to the subscribers
+fn watch_roots(roots: &[PathBuf], subscribers: Arc<Mutex<Vec<mpsc::Sender<PathBuf>>>>) -> Option<notify::RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        if event.kind.is_access() { return }
+        // subscribers whose receiving end is gone (because its thread panicked) are dropped
+        subscribers.lock().unwrap().retain(|subscriber| event.paths.iter().all(|i| subscriber.send(i.clone()).is_ok()));
+    }).inspect_err(|e| eprintln!("\x1b[91mCouldn't watch for file changes, directory sizes and content search won't update: {e}\x1b[0m")).ok()?;
+    for root in roots.iter().filter(|i| i.is_dir()) {
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            eprintln!("\x1b[91mCouldn't watch {} for file changes, its directory sizes and content search won't update: {e}\x1b[0m", root.to_string_lossy());
+        }
+    }
+    Some(watcher)
+}
+
+/// Waits for a changed path, then a little longer for more, and returns them all (None once nothing can send any more)
+pub fn receive_changes(receiver: &mpsc::Receiver<PathBuf>) -> Option<Vec<PathBuf>> {
+    let path = receiver.recv().ok()?;
+    thread::sleep(DEBOUNCE);
+    Some([path].into_iter().chain(receiver.try_iter()).collect())
+}
@@ -0,0 +1,49 @@
+use std::net::{IpAddr, SocketAddr};
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use crate::cli::ForwardedHeader;
+
+/// Returns the client's address from `header`, the one the reverse proxy in front adds
+///
+/// Only that header is looked at, since the proxy passes the others on as the client sent them. Each proxy adds the address it got the
+/// request from to the end of Forwarded and X-Forwarded-For, and everything before the last trusted proxy's came from the client, so it
+/// can be anything. The address right before the trusted proxies' is the client's, and None is returned if it can't be made sense of
+pub fn client_ip(headers: &HeaderMap, header: ForwardedHeader, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let hops = match header {
+        ForwardedHeader::Xff => header_values(headers, "x-forwarded-for").collect::<Vec<_>>(),
+        ForwardedHeader::Forwarded => header_values(headers, "forwarded")
+            .map(|i| i.split(';').find_map(|i| i.trim().split_once('=').filter(|(key, _)| key.eq_ignore_ascii_case("for")).map(|(_, value)| value)).unwrap_or_default())
+            .collect(),
+        // the proxy sets this one instead of adding to it, so there's only ever one (unless the client sent another one too)
+        ForwardedHeader::RealIp => {
+            let mut values = header_values(headers, "x-real-ip");
+            return match (values.next(), values.next()) {
+                (Some(value), None) => parse_ip(value),
+                _ => None,
+            }
+        },
+    };
+    let mut client_ip = None;
+    for hop in hops.into_iter().rev() {
+        let ip = parse_ip(hop)?;
+        client_ip = Some(ip);
+        if !trusted_proxies.iter().any(|i| i.contains(&ip)) {
+            break
+        }
+    }
+    client_ip
+}
+
+/// Returns the comma separated values of every header called `name`
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name).iter().filter_map(|i| i.to_str().ok()).flat_map(|i| i.split(',')).map(str::trim)
+}
+
+/// Parses an address that might be quoted, bracketed or have a port, like Forwarded's can be
+fn parse_ip(address: &str) -> Option<IpAddr> {
+    let address = address.trim().trim_matches('"');
+    address.parse::<IpAddr>().ok()
+        .or_else(|| address.parse::<SocketAddr>().ok().map(|i| i.ip()))
+        .or_else(|| address.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+        .map(|i| i.to_canonical())
+}
@@ -0,0 +1,102 @@
+use std::cmp::Ordering;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+/// How many entries are rendered into the page, the rest are fetched by directory.js as it's scrolled
+pub const PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Modified,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// How a listing should be sorted, filtered and paged, from the `sort`, `order`, `filter`, `offset` and `limit` query parameters
+#[derive(Debug, Clone, Default)]
+pub struct ListingOptions {
+    pub sort: SortKey,
+    descending: bool,
+    /// Lowercased, only entries with this in their name are listed
+    filter: Option<String>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// An entry with just what's needed to sort it, since making the full `EntryData` for every entry of a huge directory is too slow
+pub struct ListingItem<T> {
+    pub name: String,
+    pub directory: bool,
+    /// The modification time or size, depending on what's being sorted by, and ignored when sorting by name
+    pub sort_value: f64,
+    pub entry: T,
+}
+
+impl ListingOptions {
+    /// Without an `order`, names are sorted A to Z, and times and sizes newest and largest first, which is how the page sorts them
+    pub fn new(sort: Option<SortKey>, order: Option<SortOrder>, filter: Option<String>, offset: Option<usize>, limit: Option<usize>) -> Self {
+        let sort = sort.unwrap_or_default();
+        Self {
+            sort,
+            descending: order.map(|i| i == SortOrder::Desc).unwrap_or(sort != SortKey::Name),
+            filter: filter.filter(|i| !i.is_empty()).map(|i| i.to_lowercase()),
+            offset: offset.unwrap_or(0),
+            limit,
+        }
+    }
+
+    /// Uses `limit` as the page size if none was asked for
+    pub fn with_default_limit(mut self, limit: usize) -> Self {
+        self.limit = self.limit.or(Some(limit));
+        self
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn is_first_page(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Returns whether an entry with this name is kept by the filter
+    pub fn matches(&self, name: &str) -> bool {
+        self.filter.as_ref().is_none_or(|filter| name.to_lowercase().contains(filter))
+    }
+
+    /// Sorts the items (directories always first, like the page shows them) and returns the requested page, and how many items there are in total
+    pub fn page<T>(&self, mut items: Vec<ListingItem<T>>) -> (Vec<T>, usize) {
+        items.retain(|i| self.matches(&i.name));
+        let mut items = items.into_iter().map(|i| (ls_sort_key(&i.name), i)).collect::<Vec<_>>();
+        items.sort_unstable_by(|(a_name_key, a), (b_name_key, b)| {
+            let ordering = match self.sort {
+                SortKey::Name => a_name_key.cmp(b_name_key),
+                _ => a.sort_value.partial_cmp(&b.sort_value).unwrap_or(Ordering::Equal),
+            };
+            // ties (like a bunch of files from the same copy) are sorted by name so pages don't overlap
+            b.directory.cmp(&a.directory).then(if self.descending { ordering.reverse() } else { ordering }).then_with(|| a_name_key.cmp(b_name_key))
+        });
+        let total = items.len();
+        let page = items.into_iter().skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).map(|(_, i)| i.entry).collect();
+        (page, total)
+    }
+}
+
+// attempts to mimic ls's sorting, which seems to ignore case but places lowercase first if it comes to it
+fn ls_sort_key(filename: &str) -> String {
+    let filename_inverted_case = filename.chars().map(|i| if i.is_lowercase() { i.to_uppercase().to_string() } else { i.to_lowercase().to_string() }).collect::<String>();
+    filename.to_lowercase().to_string() + &filename_inverted_case
+}
@@ -1,22 +1,63 @@
-use std::{collections::HashMap, fs, io::{self, Write}, net::{IpAddr, SocketAddr}, os::unix::fs::MetadataExt, path::{self, Path, PathBuf}, process, str::FromStr, sync::{Arc, Mutex}, thread, time::{Duration, Instant, UNIX_EPOCH}};
-use axum::{body::Body, extract::{ConnectInfo, DefaultBodyLimit, Multipart, Query, Request, State}, http::{header::{self, CACHE_CONTROL}, HeaderValue, Response, StatusCode, Uri}, response::{Html, IntoResponse}, routing::get, Json, Router};
+use std::{ffi::{OsStr, OsString}, fs, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, os::unix::{ffi::OsStrExt, fs::{FileTypeExt, MetadataExt}}, path::{self, Path, PathBuf}, process, str::FromStr, sync::Arc, thread, time::{Duration, Instant}};
+use axum::{body::{Body, Bytes}, extract::{multipart::Field, ConnectInfo, DefaultBodyLimit, Multipart, Query, Request, State}, http::{header::{self, CACHE_CONTROL}, HeaderValue, Response, StatusCode, Uri}, middleware::{self, Next}, response::{Html, IntoResponse, Redirect}, routing::get, Json, Router};
 use askama_axum::Template;
+use archive::{ArchiveIndexes, StreamFileError};
 use chrono::Local;
-use cli::{get_args, DirectoryListingViewType};
+use cli::{get_args, DirectoryListingViewType, ForwardedHeader};
+use content_index::ContentIndex;
+use directory_sizes::DirectorySizes;
+use file_watcher::FileWatcher;
+use listing::{ListingItem, ListingOptions};
+use preview::{Preview, PreviewContent};
+use thumbnails::Thumbnails;
 use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::{OwnedSemaphorePermit, Semaphore}, task::JoinSet, net::UnixListener};
+use tokio_stream::wrappers::ReceiverStream;
+use socket2::{Domain, Protocol, Socket, Type};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use ipnet::IpNet;
+use listenfd::ListenFd;
 use tower::{ServiceBuilder, ServiceExt};
-use tower_http::{compression::CompressionLayer, services::ServeFile};
-use local_ip_address::local_ip;
+use utoipa::ToSchema;
+use tower_http::{compression::{predicate::{NotForContentType, Predicate}, CompressionLayer, DefaultPredicate}, services::ServeFile};
+use local_ip_address::{list_afinet_netifas, local_ip};
+use qrcode::{render::unicode, QrCode};
 
+mod api;
+mod archive;
 mod cli;
+mod client;
+mod content_index;
+mod directory_sizes;
+mod discovery;
+mod file_metadata;
+mod file_watcher;
+mod forwarded;
+mod listing;
+mod nested_listing;
+mod operations;
+mod preview;
+mod search;
+mod sync;
+#[cfg(test)]
+mod temporary_directory;
+mod thumbnails;
+mod tree;
+mod url_encoding;
+mod usage;
+mod versioned_cache;
 
-fn encode_url_spaces<S: AsRef<str>>(url: S) -> String {
-    url.as_ref().replace(" ", "%20")
+/// Returns the url of a request path (which is kept encoded), which handlers get without the base path, with the base path put back in front
+fn absolute_url(state: &AppState, request_path: &str) -> String {
+    state.base_path.clone() + request_path
 }
 
-fn unencode_url_spaces<S: AsRef<str>>(url: S) -> String {
-    url.as_ref().replace("%20", " ")
-}
+/// Responses from a server with a base path have it in this header, so the client can tell which part of a url it is
+const BASE_PATH_HEADER: &str = "x-gshare3-base-path";
+
+/// How many entries streamed listings and searches read with one filesystem permit
+const STREAM_BATCH_SIZE: usize = 64;
 
 // font used across the website
 const WEBSITE_FONT: &[u8; 93824] = include_bytes!("JetBrainsMono-Medium.woff2");
@@ -28,13 +69,21 @@ struct PathComponent {
     url: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 struct EntryData {
     name: String,
     url: String,
     directory: bool,
     size: Option<u64>,
+    size_computing: bool,
     modified: f32,
+    thumbnail: Option<String>,
+    mime_type: Option<String>,
+    /// Dimensions of images, so they can be laid out before they load
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Whether the file is an archive that can be browsed like a directory (by adding a slash to the url)
+    archive: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -46,12 +95,31 @@ struct PageData {
     upload_overwrite: bool,
     virtual_directory: bool,
     default_view: DirectoryListingViewType,
+    /// The directory's README, as sanitized HTML
+    readme: Option<String>,
+    /// Whether file contents can be searched with `?grep=`
+    content_search: bool,
+    /// How many entries there are (after filtering), `entries` is only part of them if it's fewer
+    total: usize,
 }
 
 #[derive(Template)]
 #[template(path = "directory/directory.jinja")]
 struct DirectoryTemplate {
     data: PageData,
+    base_path: String,
+}
+
+/* ---------------------------- Preview Template ---------------------------- */
+#[derive(Template)]
+#[template(path = "preview/preview.jinja")]
+struct PreviewTemplate {
+    base_path: String,
+    name: String,
+    url: String,
+    size: String,
+    path_components: Vec<PathComponent>,
+    preview: Preview,
 }
 
 /* ------------------------ Not Whitelisted Template ------------------------ */
@@ -59,6 +127,7 @@ struct DirectoryTemplate {
 #[template(path = "not-whitelisted.jinja")]
 struct NotWhitelistedTemplate {
     client_ip: String,
+    base_path: String,
 }
 
 /* ------------------------------ 404 Template ------------------------------ */
@@ -66,16 +135,28 @@ struct NotWhitelistedTemplate {
 #[template(path = "404.jinja")]
 struct NotFoundTemplate {
     directory: bool,
+    base_path: String,
 }
 
 /* -------------------------------- App State ------------------------------- */
 #[derive(Debug, Clone)]
 struct AppState {
     whitelisted_ips: Vec<IpAddr>,
+    /// Starts with a slash and doesn't end with one, or is empty, see absolute_url
+    base_path: String,
+    /// The addresses of this machine, which are always let in like loopback is
+    local_addresses: Vec<IpAddr>,
     paths: Vec<PathBuf>,
+    /// Whether the root is the virtual directory, because there's more than one path or the only one is a file
+    virtual_directory: bool,
+    /// Limits how much filesystem work runs at once, see run_blocking
+    filesystem_permits: Arc<Semaphore>,
     upload: bool,
     upload_overwrite: bool,
-    directory_sizes: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    directory_sizes: DirectorySizes,
+    thumbnails: Thumbnails,
+    archive_indexes: ArchiveIndexes,
+    content_index: ContentIndex,
     default_view: DirectoryListingViewType,
     title: Option<String>,
 }
@@ -83,14 +164,19 @@ struct AppState {
 #[tokio::main]
 async fn main() {
     let args = get_args();
+    if let Some(command) = args.command {
+        client::run(command).await;
+        return
+    }
 
     /* --------------------------- Get paths and IP's --------------------------- */
     let mut whitelisted_ips = Vec::new();
     let mut paths = args.paths.clone().into_iter().map(|i| path::absolute(i).unwrap()).collect::<Vec<PathBuf>>();
 
     for arg in args.paths_and_ips {
-        if let Ok(ip) = IpAddr::from_str(&arg) {
-            whitelisted_ips.push(ip);
+        // paths don't have to be UTF-8, but ips always are
+        if let Some(ip) = arg.to_str().and_then(|i| IpAddr::from_str(i).ok()) {
+            whitelisted_ips.push(ip.to_canonical());
         } else {
             paths.push(path::absolute(PathBuf::from(&arg)).unwrap())
         }
@@ -104,59 +190,41 @@ async fn main() {
     let paths_that_dont_exist = paths.iter().filter(|i| !i.exists()).collect::<Vec<&PathBuf>>();
     if !paths_that_dont_exist.is_empty() {
         for path in paths_that_dont_exist {
-            eprintln!("\x1b[91mFile or directory not found: {}", path.to_str().unwrap());
+            eprintln!("\x1b[91mFile or directory not found: {}", path.display());
         }
         process::exit(1);
     }
 
+    let local_addresses = interface_addresses();
+    let file_watcher = FileWatcher::new(paths.clone());
     let state = AppState {
         whitelisted_ips,
+        local_addresses: local_addresses.clone(),
+        base_path: args.base_path.clone(),
+        virtual_directory: paths.len() > 1 || paths[0].is_file(),
         paths: paths.clone(),
+        filesystem_permits: Arc::new(Semaphore::new(args.filesystem_jobs.get())),
         upload: args.upload,
         upload_overwrite: args.upload_overwrite,
-        directory_sizes: Arc::new(Mutex::new(HashMap::new())),
+        content_index: ContentIndex::new(paths.clone(), !args.no_content_search, file_watcher.clone()),
+        directory_sizes: DirectorySizes::new(
+            paths.clone(),
+            !args.no_directory_sizes,
+            args.directory_size_threads.unwrap_or_else(|| thread::available_parallelism().map(|i| i.get()).unwrap_or(1)),
+            if args.no_directory_size_cache { None } else { args.directory_size_cache.or_else(|| directory_sizes::default_cache_file(&paths)) },
+            file_watcher,
+        ),
+        thumbnails: Thumbnails::new(
+            !args.no_thumbnails,
+            if args.no_thumbnail_cache { None } else { args.thumbnail_cache.or_else(thumbnails::default_cache_directory) },
+        ),
+        archive_indexes: ArchiveIndexes::default(),
         default_view: args.default_view,
-        title: args.title,
+        title: args.title.clone(),
     };
 
-    /* ----------------------------- Directory Sizes ---------------------------- */
-    fn add_directory_size_recursive(path: PathBuf, directory_sizes_hashmap: &mut Arc<Mutex<HashMap<PathBuf, u64>>>) -> u64 {
-        if path.is_symlink() { return 0 }
-        if path.is_file() {
-            return path.metadata().unwrap().size()
-        }
-        if let Some(&size) = directory_sizes_hashmap.clone().lock().unwrap().get(&path) {
-            return size
-        }
-        let Ok(read_dir) = fs::read_dir(&path) else { return 0 };
-        let mut size: u64 = 0;
-        for entry in read_dir {
-            let entry = entry.unwrap();
-            // I'm guessing file_type can fail for symlinks?
-            if let Ok(file_type) = entry.file_type() {
-                if file_type.is_file() {
-                    size += entry.metadata().unwrap().size();
-                } else {
-                    size += add_directory_size_recursive(entry.path(), directory_sizes_hashmap);
-                }
-            }
-        }
-
-        directory_sizes_hashmap.clone().lock().unwrap().insert(path, size);
-        size
-    }
-
-    let mut state_directory_sizes_but_better = state.directory_sizes.clone();
-    if !args.no_directory_sizes {
-        // get directory sizes in a separate thread so you don't have to wait before accessing the website
-        thread::spawn(move || {
-            for path in paths {
-                add_directory_size_recursive(path, &mut state_directory_sizes_but_better);
-            }
-        });
-    }
-
     /* --------------------------------- Router --------------------------------- */
+    let (trusted_proxies, forwarded_header) = (args.trusted_proxy.clone(), args.forwarded_header);
     let app = Router::new()
         .route("/",
             get(get_request_handler)
@@ -166,45 +234,25 @@ async fn main() {
             get(get_request_handler)
             .post(upload_handler)
         )
+        .merge(api::router())
         .layer(
             ServiceBuilder::new()
                 .layer(DefaultBodyLimit::disable())
+                .map_request(move |request| client_address(request, forwarded_header, &trusted_proxies))
                 .map_response(|mut i: Response<Body>| {
                     i.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
                     i
                 })
         )
         .with_state(state)
-        .layer(CompressionLayer::new());
-
-    /* ------------------------------- Host Server ------------------------------ */
-    let local_ip_addr = local_ip().unwrap();
-    
-    let addr_net = if args.private { "127.0.0.1" } else { "0.0.0.0"};
-    let mut addr_host = args.port;
-    let listener = loop {
-        let addr = format!("{}:{}", addr_net, addr_host);
-        match tokio::net::TcpListener::bind(&addr).await {
-            Ok(listener) => break listener,
-            Err(error) => match error.kind() {
-                io::ErrorKind::AddrInUse => {
-                    if args.no_port_increment {
-                        eprintln!("\x1b[91mPort \x1b[96m{}\x1b[91m already in use", args.port);
-                        process::exit(1);
-                    }
-                    addr_host += 1
-                },
-                e => panic!("{}", e),
-            }
-        }
-    };
-
-    let server_started_prefix_string = if args.private {
-        "Started a \x1b[95mprivate\x1b[0m server at "
-    } else {
-        "Server started at "
+        // compressing streamed results would hold them back until enough of them build up
+        .layer(CompressionLayer::new().compress_when(DefaultPredicate::new().and(NotForContentType::const_new("application/x-ndjson"))));
+    let app = if args.base_path.is_empty() { app } else {
+        let base_path = args.base_path.clone();
+        Router::new().fallback_service(app).layer(middleware::from_fn(move |request, next| strip_base_path(base_path.clone(), request, next)))
     };
 
+    /* ------------------------------- Host Server ------------------------------ */
     let upload_suffix_string = if args.upload {
         " with \x1b[95mupload \x1b[92m(new files only)\x1b[0m"
     } else if args.upload_overwrite {
@@ -212,24 +260,324 @@ async fn main() {
     } else {
         ""
     };
-    // localhost has http:// because VSCode (a common enough editor) doesn't
-    // think it's a url otherwise, but I imagine if you're starting a public
-    // server you want to get the simplest url you can tell to others, hence
-    // it doesn't have the http
-    // PS: this is unintentionally the most perfectly aligned text I have ever written
-    println!("{}\x1b[96m{}{}:{addr_host}\x1b[0m{}{}",
-        server_started_prefix_string,
-        if args.private { String::from("http://localhost") } else { local_ip_addr.to_string() },
-        if addr_host == args.port { "" } else { "\x1b[95m" },
-        upload_suffix_string,
-        if addr_host == args.port { String::new() } else { format!(" \x1b[2m(:{} already in use)\x1b[0m", args.port) }
-    );
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    let tcp_app = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+    let mut servers = JoinSet::new();
+    // kept around for as long as the server runs, dropping it stops the advertising
+    let mut _mdns = None;
+    let mut listen_fds = ListenFd::from_env();
+    if let Some(socket_path) = &args.unix_socket {
+        let listener = match bind_unix(socket_path) {
+            Ok(listener) => listener,
+            Err(error) => {
+                eprintln!("\x1b[91mCouldn't listen on \x1b[96m{}\x1b[91m: {error}\x1b[0m", socket_path.display());
+                process::exit(1);
+            },
+        };
+        println!("Server started at \x1b[96m{}\x1b[0m{}", socket_path.display(), upload_suffix_string);
+        servers.spawn(serve_unix(listener, app));
+    } else if listen_fds.len() > 0 {
+        // systemd (or anything else using LISTEN_FDS) already made the sockets
+        let mut socket_names = Vec::new();
+        for index in 0..listen_fds.len() {
+            let result = if let Ok(Some(listener)) = listen_fds.take_tcp_listener(index) {
+                listener.set_nonblocking(true).and_then(|_| tokio::net::TcpListener::from_std(listener)).map(|listener| {
+                    socket_names.push(listener.local_addr().map(|i| i.to_string()).unwrap_or_default());
+                    let tcp_app = tcp_app.clone();
+                    servers.spawn(async move { axum::serve(listener, tcp_app).await });
+                })
+            } else if let Ok(Some(listener)) = listen_fds.take_unix_listener(index) {
+                listener.set_nonblocking(true).and_then(|_| UnixListener::from_std(listener)).map(|listener| {
+                    socket_names.push(listener.local_addr().ok().and_then(|i| i.as_pathname().map(|i| i.display().to_string())).unwrap_or_default());
+                    servers.spawn(serve_unix(listener, app.clone()));
+                })
+            } else {
+                Err(io::Error::other("it isn't a TCP or unix socket listener"))
+            };
+            if let Err(error) = result {
+                // the first one passed in is 3, right after stdin, stdout and stderr
+                eprintln!("\x1b[91mCouldn't listen on file descriptor \x1b[96m{}\x1b[91m: {error}\x1b[0m", index + 3);
+                process::exit(1);
+            }
+        }
+        println!("Server started at \x1b[96m{}\x1b[0m{}", socket_names.join(", "), upload_suffix_string);
+    } else {
+        let default_bind_addresses = args.bind.is_empty();
+        let mut bind_addresses = if !default_bind_addresses {
+            args.bind.clone()
+        } else if args.private {
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)]
+        } else {
+            vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+        };
+        // an address that's already covered by an unspecified one can't be bound on the same port, which would look like every port being in use
+        // (and the unspecified IPv6 address covers IPv4 too, since it's listened on with both)
+        let unspecified_addresses = bind_addresses.iter().filter(|i| i.is_unspecified()).copied().collect::<Vec<_>>();
+        bind_addresses.retain(|i| !unspecified_addresses.iter().any(|j| j != i && (j.is_ipv6() || j.is_ipv4() == i.is_ipv4())));
+        bind_addresses.dedup();
+
+        let mut addr_host = args.port;
+        let listeners = 'ports: loop {
+            let mut listeners = Vec::new();
+            for address in bind_addresses.clone() {
+                match bind(SocketAddr::new(address, addr_host)) {
+                    Ok(listener) => listeners.push(listener),
+                    Err(error) if error.kind() == io::ErrorKind::AddrInUse => {
+                        if args.no_port_increment {
+                            eprintln!("\x1b[91mPort \x1b[96m{}\x1b[91m already in use", args.port);
+                            process::exit(1);
+                        }
+                        addr_host += 1;
+                        continue 'ports;
+                    },
+                    // IPv6 can be turned off, which only matters if it was asked for
+                    Err(_) if default_bind_addresses && address.is_ipv6() => {
+                        bind_addresses.retain(|i| *i != address);
+                        if address.is_unspecified() {
+                            bind_addresses.push(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                        }
+                        continue 'ports;
+                    },
+                    Err(error) => {
+                        eprintln!("\x1b[91mCouldn't listen on \x1b[96m{}\x1b[91m: {error}\x1b[0m", SocketAddr::new(address, addr_host));
+                        process::exit(1);
+                    },
+                }
+            }
+            break listeners
+        };
+        let addresses = reachable_addresses(&bind_addresses, &local_addresses);
+        let private = addresses.iter().all(|i| i.is_loopback());
+
+        let server_started_prefix_string = if private {
+            "Started a \x1b[95mprivate\x1b[0m server at "
+        } else {
+            "Server started at "
+        };
+
+        // localhost has http:// because VSCode (a common enough editor) doesn't
+        // think it's a url otherwise, but I imagine if you're starting a public
+        // server you want to get the simplest url you can tell to others, hence
+        // it doesn't have the http
+        // PS: this is unintentionally the most perfectly aligned text I have ever written
+        println!("{}\x1b[96m{}{}:{addr_host}{}\x1b[0m{}{}",
+            server_started_prefix_string,
+            banner_host(&addresses[0]),
+            if addr_host == args.port { "" } else { "\x1b[95m" },
+            args.base_path,
+            upload_suffix_string,
+            if addr_host == args.port { String::new() } else { format!(" \x1b[2m(:{} already in use)\x1b[0m", args.port) }
+        );
+        // the other addresses go under the first one
+        let indent = if private { "Started a private server at ".len() } else { server_started_prefix_string.len() };
+        for address in &addresses[1..] {
+            println!("{}\x1b[96m{}:{addr_host}{}\x1b[0m", " ".repeat(indent), banner_host(address), args.base_path);
+        }
+        if args.qr {
+            // the banner leaves out the http:// for public servers, but phones need it to tell the code is a link
+            print_qr_code(&format!("http://{}:{addr_host}{}/", url_host(&addresses[0]), args.base_path));
+        }
+        if !args.no_mdns {
+            _mdns = discovery::advertise(discovery::Advertisement {
+                title: args.title,
+                upload: if args.upload_overwrite { "overwrite" } else if args.upload { "new" } else { "none" },
+                port: addr_host,
+                path: args.base_path.clone() + "/",
+                private,
+            });
+        }
+        for listener in listeners {
+            let tcp_app = tcp_app.clone();
+            servers.spawn(async move { axum::serve(listener, tcp_app).await });
+        }
+    }
+    while let Some(result) = servers.join_next().await {
+        result.unwrap().unwrap();
+    }
+}
+
+/// Listens on `address` like TcpListener::bind, but with the unspecified IPv6 address taking IPv4 connections too
+///
+/// Whether it does otherwise depends on the system (`net.ipv6.bindv6only` on linux), and any other IPv6 address is only listened on with IPv6
+fn bind(address: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!address.ip().is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Listens on a unix socket at `path`, replacing the one a previous run left behind
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if path.symlink_metadata().is_ok_and(|i| i.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Marks requests that came in through a unix socket, which only a reverse proxy connects to
+#[derive(Debug, Clone, Copy)]
+struct UnixSocketConnection;
+
+/// Serves `app` on a unix socket, which axum::serve only does with TCP
+///
+/// There's no client address to go by, so client_address takes it from the headers the reverse proxy in front adds
+async fn serve_unix(listener: UnixListener, app: Router) -> io::Result<()> {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else { continue };
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)));
+            request.extensions_mut().insert(UnixSocketConnection);
+            app.clone().oneshot(request)
+        });
+        tokio::spawn(async move {
+            // errors are only from clients going away, axum::serve ignores them too
+            let _ = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new()).serve_connection_with_upgrades(TokioIo::new(stream), service).await;
+        });
+    }
+}
+
+/// Sets the address a request is from to the client's, rather than whatever connected
+///
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses, which are turned back into IPv4 ones so they're logged
+/// and whitelisted like they would be otherwise. Requests from trusted proxies (and unix sockets) are from whoever the proxy's
+/// `forwarded_header` says, or from an unspecified address, which isn't let in when there's a whitelist, if it doesn't say
+fn client_address(mut request: Request, forwarded_header: ForwardedHeader, trusted_proxies: &[IpNet]) -> Request {
+    let Some(ConnectInfo(address)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied() else { return request };
+    let mut ip = address.ip().to_canonical();
+    if request.extensions().get::<UnixSocketConnection>().is_some() || trusted_proxies.iter().any(|i| i.contains(&ip)) {
+        ip = forwarded::client_ip(request.headers(), forwarded_header, trusted_proxies).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+    request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, address.port())));
+    request
+}
+
+/// Takes the base path off the front of requests, so the handlers see the same paths they would without one (it's added back to the urls
+/// they make by absolute_url)
+///
+/// The base path itself is redirected to the one with a slash, like the root always has, or relative links would be missing it
+async fn strip_base_path(base_path: String, request: Request, next: Next) -> Response<Body> {
+    let mut response = strip_base_path_inner(&base_path, request, next).await;
+    // even on requests outside of it, so the client can tell what went wrong
+    if let Ok(value) = HeaderValue::from_str(&base_path) {
+        response.headers_mut().insert(BASE_PATH_HEADER, value);
+    }
+    response
+}
+
+async fn strip_base_path_inner(base_path: &str, mut request: Request, next: Next) -> Response<Body> {
+    let Some(path) = request.uri().path().strip_prefix(base_path).filter(|i| i.is_empty() || i.starts_with('/')) else {
+        return StatusCode::NOT_FOUND.into_response()
+    };
+    if path.is_empty() {
+        return Redirect::permanent(&(base_path.to_string() + "/")).into_response()
+    }
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+/// Returns the addresses of this machine's network interfaces, or none if they can't be listed
+///
+/// Link-local IPv6 addresses are left out, since they only work with a zone id, which browsers don't take
+fn interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = list_afinet_netifas().map(|i| i.into_iter().map(|(_, ip)| ip).collect::<Vec<_>>()).unwrap_or_default();
+    addresses.retain(|i| !matches!(i, IpAddr::V6(ip) if ip.is_unicast_link_local()));
+    addresses.sort_unstable();
+    addresses.dedup();
+    addresses
+}
+
+/// Returns the addresses clients can reach the server at, starting with the one most likely to work for others
+///
+/// Unspecified addresses are listened on with every interface, loopback is only included when there's nothing else
+fn reachable_addresses(bind_addresses: &[IpAddr], local_addresses: &[IpAddr]) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    for bind_address in bind_addresses {
+        if bind_address.is_unspecified() {
+            addresses.extend(local_addresses.iter().filter(|i| (bind_address.is_ipv6() || i.is_ipv4()) && !i.is_loopback()));
+        } else {
+            addresses.push(*bind_address);
+        }
+    }
+    if addresses.is_empty() {
+        addresses.push(if bind_addresses.iter().all(|i| i.is_ipv6()) { IpAddr::V6(Ipv6Addr::LOCALHOST) } else { IpAddr::V4(Ipv4Addr::LOCALHOST) });
+    }
+    // the one with the default route is what other people are most likely to be able to reach
+    let default_route_address = local_ip().ok();
+    addresses.sort_unstable_by_key(|i| (Some(*i) != default_route_address, i.is_loopback(), i.is_ipv6(), *i));
+    // both loopback addresses are localhost
+    addresses.dedup_by_key(|i| url_host(i));
+    addresses
+}
+
+/// Returns how an address goes in a url, with loopback as localhost
+fn url_host(ip: &IpAddr) -> String {
+    match ip {
+        ip if ip.is_loopback() => String::from("localhost"),
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+/// Returns how an address goes in the startup banner, where only localhost gets the http:// (see the comment there)
+fn banner_host(ip: &IpAddr) -> String {
+    if ip.is_loopback() { String::from("http://localhost") } else { url_host(ip) }
+}
+
+/// Prints `url` as a QR code made of unicode half blocks, two rows of modules to a line
+fn print_qr_code(url: &str) {
+    let Ok(code) = QrCode::new(url) else { return };
+    // the colors are swapped since terminals are usually dark, which would make the code come out inverted
+    let image = code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    println!("{image}");
 }
 
 /// Returns a boolean value indicating whether the IP is whitelisted
 fn ip_authorized(state: &AppState, ip: &IpAddr) -> bool {
-    state.whitelisted_ips.is_empty() || state.whitelisted_ips.contains(&ip) || ip.is_loopback() || *ip == local_ip().unwrap()
+    let ip = &ip.to_canonical();
+    state.whitelisted_ips.is_empty() || state.whitelisted_ips.contains(ip) || ip.is_loopback() || state.local_addresses.contains(ip)
+}
+
+/// Runs filesystem work on the blocking thread pool, so a slow disk (or network mount) doesn't stall every other request
+///
+/// Everything that touches the disk or is slow (listing, hashing, reading archives, thumbnails, highlighting previews) goes through
+/// this, or through filesystem_permit when it's streamed
+///
+/// Only `--fs-jobs` of these run at once, the rest wait their turn, so a flood of requests to a slow mount
+/// doesn't pile up hundreds of stuck threads
+async fn run_blocking<T: Send + 'static>(state: &AppState, f: impl FnOnce() -> T + Send + 'static) -> T {
+    let permit = filesystem_permit(state).await;
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    }).await.unwrap()
+}
+
+/// Returns a permit for filesystem work that streams its results, which is held while it reads the first batch of them,
+/// see blocking_filesystem_permit for the rest
+async fn filesystem_permit(state: &AppState) -> OwnedSemaphorePermit {
+    state.filesystem_permits.clone().acquire_owned().await.unwrap()
+}
+
+/// Waits for a filesystem permit on a blocking thread, for work that streams its results and takes one for each batch it reads,
+/// rather than keeping one while it waits for the client to take what it sent
+fn blocking_filesystem_permit(permits: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    tokio::runtime::Handle::current().block_on(permits.clone().acquire_owned()).unwrap()
 }
 
 /* ------------------------ Figuring out request path ----------------------- */
@@ -242,28 +590,31 @@ enum FiguredOutRequestPath {
     VirtualDirectory,
     Directory(PathBuf),
     File(PathBuf),
+    /// A directory inside an archive, as the archive's path and the path inside it
+    ArchiveDirectory(PathBuf, String),
+    /// A file inside an archive, as the archive's path and the path inside it
+    ArchiveFile(PathBuf, String),
     NotFound,
 }
 
 fn figure_out_request_path<S: AsRef<str>>(state: &AppState, request_path: S) -> FiguredOutRequestPath {
     let request_path = request_path.as_ref();
     // virtual directory fileserver
-    if state.paths.len() > 1 || state.paths[0].is_file() {
-        if request_path == "" || request_path == "/" {
+    if state.virtual_directory {
+        if request_path.split('/').all(str::is_empty) {
             return FiguredOutRequestPath::VirtualDirectory;
         }
-        let mut request_path_components = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
         // todo: change this to a bad request or a redirect or something
-        if request_path_components.contains(&"..") {
+        let Some(mut request_path_components) = decode_path_components(request_path) else {
             return FiguredOutRequestPath::NotFound;
-        }
+        };
         let request_top_level_path_component = request_path_components.remove(0);
-        let server_top_level_path = match state.paths.clone().into_iter().find(|i| i.file_name().unwrap().to_str().unwrap() == request_top_level_path_component) {
+        let server_top_level_path = match state.paths.clone().into_iter().find(|i| i.file_name() == Some(&request_top_level_path_component)) {
             Some(path) => path,
             None => return FiguredOutRequestPath::NotFound,
         };
         let final_path = if request_path_components.is_empty() {
-            server_top_level_path
+            server_top_level_path.clone()
         } else {
             server_top_level_path.join(request_path_components.iter().collect::<PathBuf>())
         };
@@ -274,16 +625,15 @@ fn figure_out_request_path<S: AsRef<str>>(state: &AppState, request_path: S) ->
         if !directory_requested && final_path.is_file() {
             return FiguredOutRequestPath::File(final_path)
         }
-        return FiguredOutRequestPath::NotFound;
+        return figure_out_archive_path(&server_top_level_path, &request_path_components, directory_requested);
     }
 
     // single directory fileserver
     let root_directory = &state.paths[0];
-    let request_path_components = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
     // todo: change this to a bad request or a redirect or something
-    if request_path_components.contains(&"..") {
+    let Some(request_path_components) = decode_path_components(request_path) else {
         return FiguredOutRequestPath::NotFound;
-    }
+    };
     let final_path = root_directory.join(request_path_components.iter().collect::<PathBuf>());
     let directory_requested = request_path.ends_with('/');
     if directory_requested && final_path.is_dir() {
@@ -292,17 +642,101 @@ fn figure_out_request_path<S: AsRef<str>>(state: &AppState, request_path: S) ->
     if !directory_requested && final_path.is_file() {
         return FiguredOutRequestPath::File(final_path)
     }
-    return FiguredOutRequestPath::NotFound;
+    figure_out_archive_path(root_directory, &request_path_components, directory_requested)
+}
+
+/// Decodes the names in an (encoded) request path, or returns None if one of them isn't just a name, like `..` or `a%2Fb`,
+/// which could reach outside the shared directories
+fn decode_path_components(request_path: &str) -> Option<Vec<OsString>> {
+    request_path.split('/').filter(|i| !i.is_empty()).map(|i| {
+        let name = url_encoding::decode_name(i);
+        (name != ".." && !name.as_bytes().contains(&b'/') && !name.as_bytes().contains(&0)).then_some(name)
+    }).collect()
+}
+
+/// Looks for an archive partway through the path, like the zip in `/bundle.zip/docs/readme.txt`
+fn figure_out_archive_path(base: &Path, request_path_components: &[OsString], directory_requested: bool) -> FiguredOutRequestPath {
+    for index in 0..=request_path_components.len() {
+        // joining an empty path adds a trailing slash, which makes is_file false even for files
+        let path = if index == 0 { base.to_path_buf() } else { base.join(request_path_components[..index].iter().collect::<PathBuf>()) };
+        if path.is_file() {
+            if archive::archive_kind(&path).is_none() {
+                return FiguredOutRequestPath::NotFound
+            }
+            // archives store their paths as text, so names that aren't UTF-8 won't be in one anyway
+            let inner_path = request_path_components[index..].iter().map(|i| i.to_string_lossy()).collect::<Vec<_>>().join("/");
+            return if directory_requested {
+                FiguredOutRequestPath::ArchiveDirectory(path, inner_path)
+            } else if !inner_path.is_empty() {
+                FiguredOutRequestPath::ArchiveFile(path, inner_path)
+            } else {
+                FiguredOutRequestPath::NotFound
+            }
+        }
+        if !path.is_dir() {
+            return FiguredOutRequestPath::NotFound
+        }
+    }
+    FiguredOutRequestPath::NotFound
+}
+
+/// Lets boolean query parameters be written as `?a=1` or just `?a`, not only `?a=true`
+fn deserialize_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(!["0", "false", "no"].contains(&value.as_str()))
+}
+
+/// How `?data` listings are returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DataFormat {
+    /// The whole `PageData` at once
+    Json,
+    /// One `EntryData` per line, streamed as the directory is read
+    Ndjson,
+}
+
+/// Lets `?data=ndjson` ask for streamed entries, anything else that isn't false is the usual JSON
+fn deserialize_data_format<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<DataFormat>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(match value.as_str() {
+        "ndjson" => Some(DataFormat::Ndjson),
+        "0" | "false" | "no" => None,
+        _ => Some(DataFormat::Json),
+    })
 }
 
 #[derive(Deserialize)]
 struct GetRequestQuery {
-    data: bool,
+    #[serde(default, deserialize_with = "deserialize_data_format")]
+    data: Option<DataFormat>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    view: bool,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    usage: bool,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    tree: bool,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    manifest: bool,
+    thumb: Option<u32>,
+    depth: Option<usize>,
+    limit: Option<usize>,
+    search: Option<String>,
+    mode: Option<search::SearchMode>,
+    grep: Option<String>,
+    hash: Option<String>,
+    sort: Option<listing::SortKey>,
+    order: Option<listing::SortOrder>,
+    filter: Option<String>,
+    offset: Option<usize>,
 }
 
 async fn get_request_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, request: Request) -> impl IntoResponse {
-    let query: Result<Query<GetRequestQuery>, _> = Query::try_from_uri(request.uri());
-    let is_data_request = query.is_ok_and(|i| i.data);
+    // like `?limit=ten`, which would otherwise quietly be no limit at all
+    let query: GetRequestQuery = match Query::try_from_uri(request.uri()) {
+        Ok(Query(query)) => query,
+        Err(rejection) => return (StatusCode::BAD_REQUEST, rejection.body_text()).into_response(),
+    };
+    let is_data_request = query.data.is_some();
     // font is always served because it's used in not-whitelisted page
     // todo: use get_unique_path to always get unique resources dir
     if request.uri().path() == "/gshare3-resources/JetBrainsMono-Medium.woff2" && !is_data_request {
@@ -316,7 +750,7 @@ async fn get_request_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(s
 
     if !ip_authorized(&state, &addr.ip()) {
         println!("\x1b[2m{} \x1b[0;96m{}\x1b[0;2m tried to connect but isn't whitelisted\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
-        let template = NotWhitelistedTemplate { client_ip: addr.ip().to_string() };
+        let template = NotWhitelistedTemplate { client_ip: addr.ip().to_string(), base_path: state.base_path.clone() };
         return (
             StatusCode::UNAUTHORIZED,
             [
@@ -327,108 +761,216 @@ async fn get_request_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(s
     }
 
     
-    let request_path = unencode_url_spaces(request.uri().path());
-    let figured_out_path = figure_out_request_path(&state, &request_path);
+    // the path stays encoded, since that's what urls are made from, and is only decoded to be shown
+    let request_path = url_encoding::normalize_path(request.uri().path());
+    let shown_path = url_encoding::decode_lossy(&request_path);
+    let figured_out_path = {
+        let (resolve_state, resolve_path) = (state.clone(), request_path.clone());
+        run_blocking(&state, move || figure_out_request_path(&resolve_state, resolve_path)).await
+    };
     if figured_out_path == FiguredOutRequestPath::NotFound {
-        println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path);
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
         return (
             StatusCode::NOT_FOUND,
             [
                 (header::CONTENT_TYPE, "text/html"),
             ],
-            NotFoundTemplate { directory: request_path.ends_with("/") }.render().unwrap()
+            NotFoundTemplate { directory: request_path.ends_with("/"), base_path: state.base_path.clone() }.render().unwrap()
         ).into_response()
     }
     if let FiguredOutRequestPath::File(path) = figured_out_path {
         if is_data_request {
-            return StatusCode::NOT_IMPLEMENTED.into_response()
+            return match operations::metadata(&state, FiguredOutRequestPath::File(path), &request_path, query.hash.as_deref()).await {
+                Ok(metadata) => {
+                    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                    Json(metadata).into_response()
+                },
+                Err(e) => {
+                    println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {} \x1b[2m({})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path, e.message());
+                    e.into_page_response()
+                },
+            }
+        }
+        if query.view {
+            let preview_path = path.clone();
+            let Ok((preview, size)) = run_blocking(&state, move || preview::preview(&preview_path).map(|i| (i, preview_path.metadata().map(|i| i.size()).unwrap_or(0)))).await else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            };
+            let path_segments = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+            let segment = path_segments.last().unwrap().to_string();
+            let name = url_encoding::decode_lossy(&segment);
+            let mut path_components = get_path_components(&state, &path_segments[..path_segments.len() - 1]);
+            path_components.push(PathComponent { name: wrap_empty_string_in_quotation_marks(&name), url: segment.clone() + "?view=1" });
+            println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+            return Html(PreviewTemplate {
+                base_path: state.base_path.clone(),
+                url: segment,
+                name,
+                size: preview::human_readable_size(size),
+                path_components,
+                preview,
+            }.render().unwrap()).into_response()
         }
-        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path);
+        if let Some(size) = query.thumb {
+            let thumbnails = state.thumbnails.clone();
+            return match run_blocking(&state, move || thumbnails.get(&path, size)).await {
+                Some(thumbnail) => (
+                    [
+                        (header::CONTENT_TYPE, thumbnail.content_type),
+                    ],
+                    thumbnail.bytes,
+                ).into_response(),
+                None => StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response(),
+            }
+        }
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
         return ServeFile::new(path).oneshot(request).await.unwrap().into_response()
     }
-    // a directory is returned
-    let mut entry_paths = Vec::new();
-    if let FiguredOutRequestPath::Directory(path) = &figured_out_path {
-        // todo: gray-out the folder if you can't read it
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries {
-                let entry = entry.unwrap();
-                if ["..", "."].contains(&entry.file_name().to_str().unwrap()) {
-                    continue;
-                }
-                entry_paths.push(entry.path());
-            }
+    if let FiguredOutRequestPath::ArchiveFile(archive_path, inner_path) = &figured_out_path {
+        if is_data_request || query.thumb.is_some() {
+            return StatusCode::NOT_IMPLEMENTED.into_response()
+        }
+        return match archive::stream_file(&state.archive_indexes, archive_path, inner_path, state.filesystem_permits.clone()).await {
+            Ok(Some((size, body))) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                (
+                    [
+                        (header::CONTENT_TYPE, mime_guess::from_path(inner_path).first_or_octet_stream().to_string()),
+                        (header::CONTENT_LENGTH, size.to_string()),
+                    ],
+                    body,
+                ).into_response()
+            },
+            Ok(None) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                (
+                    StatusCode::NOT_FOUND,
+                    [
+                        (header::CONTENT_TYPE, "text/html"),
+                    ],
+                    NotFoundTemplate { directory: false, base_path: state.base_path.clone() }.render().unwrap()
+                ).into_response()
+            },
+            Err(e) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {} \x1b[2m({e})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                match e {
+                    StreamFileError::Unreadable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                    StreamFileError::Panicked => StatusCode::INTERNAL_SERVER_ERROR,
+                }.into_response()
+            },
         }
-    } else {
-        entry_paths.extend(state.paths.clone().into_iter());
     }
-
-    // attempts to mimic ls's sorting, which seems to ignore case but places lowercase first if it comes to it
-    entry_paths.sort_unstable_by_key(|i| {
-        let filename = i.file_name().unwrap().to_str().unwrap();
-        let filename_inverted_case = filename.chars().map(|i| if i.is_lowercase() { i.to_uppercase().to_string() } else { i.to_lowercase().to_string() }).collect::<String>();
-        filename.to_lowercase().to_string() + &filename_inverted_case
-    });
-
-    let path_component_names = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
-    let mut path_components = Vec::new();
-    path_components.push(if state.paths.len() > 1 || state.paths[0].is_file() {
-        PathComponent { name: String::from("Virtual Directory"), url: String::from("/") }
-    } else {
-        PathComponent { name: String::from("Root Directory"), url: String::from("/") }
-    });
-
-    let mut entries = Vec::new();
-    for path in entry_paths {
-        if path.is_symlink() {
-            continue;
+    if query.usage {
+        if matches!(figured_out_path, FiguredOutRequestPath::ArchiveDirectory(..)) {
+            return StatusCode::NOT_IMPLEMENTED.into_response()
         }
-        let name = path.file_name().unwrap().to_string_lossy().to_string();
-        let metadata = path.metadata().unwrap();
-        if path.is_dir() {
-            entries.push(EntryData {
-                name: name.clone(),
-                url: if !is_data_request {
-                    encode_url_spaces(name.clone() + "/")
-                } else {
-                    encode_url_spaces(String::from("/") + &path_component_names.join("/") + if path_component_names.is_empty() {""} else {"/"} + &name.clone() + "/")
-                },
-                directory: true,
-                size: state.directory_sizes.clone().lock().unwrap().get(&path).and_then(|i| Some(i.clone())),
-                modified: metadata.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs_f32(),
-            })
-        } else {
-            entries.push(EntryData {
-                name: name.clone(),
-                url: if !is_data_request {
-                    encode_url_spaces(name.clone())
-                } else {
-                    encode_url_spaces(String::from("/") + &path_component_names.join("/") + if path_component_names.is_empty() {""} else {"/"} + &name.clone())
-                },
-                directory: false,
-                size: Some(metadata.size()),
-                modified: metadata.modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs_f32(),
-            })
+        let depth = query.depth.unwrap_or(usage::DEFAULT_DEPTH).min(nested_listing::MAX_DEPTH);
+        let limit = query.limit.unwrap_or(usage::DEFAULT_LIMIT);
+        let (usage_state, usage_path, url) = (state.clone(), figured_out_path.clone(), absolute_url(&state, &request_path));
+        let usage = run_blocking(&state, move || match &usage_path {
+            FiguredOutRequestPath::Directory(path) => {
+                let name = path.file_name().map(|i| i.to_string_lossy().to_string()).unwrap_or(String::from("Root Directory"));
+                usage::directory_usage(&usage_state, path, name, url, depth, limit)
+            },
+            _ => usage::virtual_directory_usage(&usage_state, String::from("Virtual Directory"), depth, limit),
+        }).await;
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+        return Json(usage).into_response()
+    }
+    if query.tree {
+        if matches!(figured_out_path, FiguredOutRequestPath::ArchiveDirectory(..)) {
+            return StatusCode::NOT_IMPLEMENTED.into_response()
         }
+        let (tree_state, tree_path, url, depth) = (state.clone(), figured_out_path.clone(), absolute_url(&state, &request_path), query.depth);
+        let tree = run_blocking(&state, move || match &tree_path {
+            FiguredOutRequestPath::Directory(path) => {
+                let name = path.file_name().map(|i| i.to_string_lossy().to_string()).unwrap_or(String::from("Root Directory"));
+                tree::directory_tree(&tree_state, path, name, url, depth)
+            },
+            _ => tree::virtual_directory_tree(&tree_state, String::from("Virtual Directory"), depth),
+        }).await;
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+        return Json(tree).into_response()
     }
-
-    fn wrap_empty_string_in_quotation_marks<S: AsRef<str>>(string: S) -> String {
-        let string = string.as_ref().to_string();
-        if string.trim().is_empty() {
-            format!("\"{string}\"")
-        } else {
-            string
+    if query.manifest {
+        return match operations::manifest(&state, figured_out_path, &request_path, query.hash.as_deref()).await {
+            Ok(manifest) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                Json(manifest).into_response()
+            },
+            Err(e) => e.into_page_response(),
         }
     }
-
-    for (index, name) in path_component_names.iter().enumerate() {
-        path_components.push(PathComponent {
-            name: wrap_empty_string_in_quotation_marks(name.to_string()),
-            url: String::from("/") + &path_component_names[..=index].join("/") + "/"
-        });
+    if let Some(search_query) = query.search.filter(|i| !i.is_empty()) {
+        let (roots_state, roots_path, roots_request_path) = (state.clone(), figured_out_path.clone(), request_path.clone());
+        let Some(roots) = run_blocking(&state, move || get_search_roots(&roots_state, &roots_path, &roots_request_path)).await else {
+            return StatusCode::NOT_IMPLEMENTED.into_response()
+        };
+        let limit = query.limit.unwrap_or(search::DEFAULT_LIMIT);
+        let permit = filesystem_permit(&state).await;
+        return match search::search(state, permit, roots, &search_query, query.mode.unwrap_or_default(), limit) {
+            Ok(body) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {} \x1b[2m(search: {search_query})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/x-ndjson"),
+                    ],
+                    body,
+                ).into_response()
+            },
+            Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        }
+    }
+    if let Some(grep_query) = query.grep.filter(|i| !i.is_empty()) {
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {} \x1b[2m(grep: {grep_query})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+        return match operations::grep(&state, figured_out_path, &request_path, grep_query, query.limit).await {
+            Ok(results) => Json(results).into_response(),
+            Err(e) => e.into_page_response(),
+        }
+    }
+    let path_segments = request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+    let listing_options = ListingOptions::new(query.sort, query.order, query.filter, query.offset, query.limit);
+    // the page gets the first page rendered in and fetches the rest as it's scrolled, the api gets everything unless it asks otherwise
+    let listing_options = if is_data_request { listing_options } else { listing_options.with_default_limit(listing::PAGE_SIZE) };
+    // archives have to be read all at once anyway, so they're only put in lines at the end
+    if query.data == Some(DataFormat::Ndjson) && !matches!(figured_out_path, FiguredOutRequestPath::ArchiveDirectory(..)) {
+        println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+        let path_segments = path_segments.iter().map(|i| i.to_string()).collect();
+        let permit = filesystem_permit(&state).await;
+        return ndjson_response(stream_directory_entries(state, permit, figured_out_path, path_segments, listing_options))
     }
+    // a directory is returned
+    let (entries, total, readme) = if let FiguredOutRequestPath::ArchiveDirectory(archive_path, inner_path) = &figured_out_path {
+        let (archive_indexes, entries_archive_path) = (state.archive_indexes.clone(), archive_path.clone());
+        let archive_entries = match run_blocking(&state, move || archive_indexes.entries(&entries_archive_path)).await {
+            Ok(archive_entries) => archive_entries,
+            Err(e) => {
+                println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {} \x1b[2m(couldn't read archive: {e})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+                return StatusCode::UNPROCESSABLE_ENTITY.into_response()
+            },
+        };
+        let Some(archive_entries) = archive::directory_entries(&archive_entries, inner_path) else {
+            println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+            return (
+                StatusCode::NOT_FOUND,
+                [
+                    (header::CONTENT_TYPE, "text/html"),
+                ],
+                NotFoundTemplate { directory: true, base_path: state.base_path.clone() }.render().unwrap()
+            ).into_response()
+        };
+        let (entries, total) = get_archive_entries(&state, archive_entries, &path_segments, is_data_request, &listing_options);
+        (entries, total, None)
+    } else {
+        let (listing_state, listing_path, listing_request_path) = (state.clone(), figured_out_path.clone(), request_path.clone());
+        run_blocking(&state, move || {
+            let path_segments = listing_request_path.split('/').filter(|i| !i.is_empty()).collect::<Vec<&str>>();
+            get_directory_entries(&listing_state, &listing_path, &path_segments, is_data_request, &listing_options)
+        }).await
+    };
 
-    let title = wrap_empty_string_in_quotation_marks(path_component_names.last().unwrap_or(&state.title.unwrap_or(String::from("gshare3")).as_str()).to_string());
+    let path_components = get_path_components(&state, &path_segments);
+    let title = wrap_empty_string_in_quotation_marks(path_segments.last().map(url_encoding::decode_lossy).unwrap_or(state.title.unwrap_or(String::from("gshare3"))));
 
     let data = PageData {
         title,
@@ -438,37 +980,289 @@ async fn get_request_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(s
         upload_overwrite: state.upload_overwrite,
         virtual_directory: figured_out_path == FiguredOutRequestPath::VirtualDirectory,
         default_view: state.default_view,
+        readme,
+        content_search: state.content_index.enabled(),
+        total,
+    };
+
+    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
+    match query.data {
+        None => Html(DirectoryTemplate { data, base_path: state.base_path.clone() }.render().unwrap()).into_response(),
+        Some(DataFormat::Json) => Json(data).into_response(),
+        Some(DataFormat::Ndjson) => ndjson_response(Body::from(data.entries.iter().flat_map(ndjson_line).collect::<Vec<u8>>())),
+    }
+}
+
+fn ndjson_response(body: Body) -> Response<Body> {
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+        ],
+        body,
+    ).into_response()
+}
+
+fn ndjson_line(entry: &EntryData) -> Vec<u8> {
+    let mut line = serde_json::to_vec(entry).unwrap();
+    line.push(b'\n');
+    line
+}
+
+/// Returns a body that streams the entries of a real directory (or the virtual directory) as they're read, one JSON `EntryData` per line
+///
+/// They come in whatever order the directory is read in, so only `filter`, `offset` and `limit` apply
+fn stream_directory_entries(state: AppState, permit: OwnedSemaphorePermit, figured_out_path: FiguredOutRequestPath, path_segments: Vec<String>, listing_options: ListingOptions) -> Body {
+    let entries_state = state.clone();
+    stream_entries(state, permit, move || {
+        let entry_paths: Box<dyn Iterator<Item = PathBuf>> = match &figured_out_path {
+            FiguredOutRequestPath::Directory(path) => match fs::read_dir(path) {
+                Ok(read_dir) => Box::new(read_dir.flatten().map(|i| i.path())),
+                Err(_) => Box::new(std::iter::empty()),
+            },
+            _ => Box::new(entries_state.paths.clone().into_iter()),
+        };
+        let (offset, limit) = (listing_options.offset(), listing_options.limit().unwrap_or(usize::MAX));
+        entry_paths
+            .filter(move |path| path.file_name().is_some_and(|i| listing_options.matches(&i.to_string_lossy())))
+            .filter_map(move |path| {
+                let path_segments = path_segments.iter().map(String::as_str).collect::<Vec<_>>();
+                get_entry_data(&entries_state, &path, |name, directory| get_entry_url(&path_segments, name, directory, Some(&entries_state.base_path)))
+            })
+            .skip(offset)
+            .take(limit)
+    })
+}
+
+/// Returns a body that streams what `entries` makes, one JSON `EntryData` per line
+///
+/// Reading directories and getting metadata blocks, so `entries` is made and read from on another thread and the lines are sent over.
+/// `permit` is held while the first batch is read, and another one is waited for before each batch after that, so a client that's slow
+/// to take what's sent (or stopped taking it) doesn't keep one from everything else
+fn stream_entries<I: Iterator<Item = EntryData>>(state: AppState, permit: OwnedSemaphorePermit, entries: impl FnOnce() -> I + Send + 'static) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Bytes, io::Error>>(STREAM_BATCH_SIZE);
+    tokio::task::spawn_blocking(move || {
+        let mut entries = entries();
+        let mut permit = Some(permit);
+        loop {
+            let batch_permit = permit.take().unwrap_or_else(|| blocking_filesystem_permit(&state.filesystem_permits));
+            let batch = entries.by_ref().take(STREAM_BATCH_SIZE).collect::<Vec<_>>();
+            drop(batch_permit);
+            if batch.is_empty() {
+                return
+            }
+            for entry in batch {
+                // the client went away, so there's no point reading the rest
+                if sender.blocking_send(Ok(Bytes::from(ndjson_line(&entry)))).is_err() {
+                    return
+                }
+            }
+        }
+    });
+    Body::from_stream(ReceiverStream::new(receiver))
+}
+
+/// Returns the paths searched by `?search=` and `?grep=` (and listed by `?manifest=`) in a directory, with their (already encoded) urls and their paths relative to the directory,
+/// or None if it isn't a real directory or the virtual directory
+fn get_search_roots(state: &AppState, figured_out_path: &FiguredOutRequestPath, request_path: &str) -> Option<Vec<(PathBuf, String, String)>> {
+    match figured_out_path {
+        FiguredOutRequestPath::Directory(path) => Some(vec![(path.clone(), absolute_url(state, request_path), String::new())]),
+        FiguredOutRequestPath::VirtualDirectory => Some(state.paths.iter().map(|path| {
+            let name = path.file_name().unwrap_or_default();
+            (path.clone(), format!("{}/{}{}", state.base_path, url_encoding::encode_name(name), if path.is_dir() { "/" } else { "" }), name.to_string_lossy().to_string())
+        }).collect()),
+        _ => None,
+    }
+}
+
+/// Returns the url of an entry in a listing, which is just the name (relative to the page) unless there's a `base_path` to start it with
+/// and the (encoded) `path_segments` of the directory it's in
+fn get_entry_url(path_segments: &[&str], name: &OsStr, directory: bool, base_path: Option<&str>) -> String {
+    let name = url_encoding::encode_name(name) + if directory { "/" } else { "" };
+    match base_path {
+        None => name,
+        Some(base_path) => String::from(base_path) + "/" + &path_segments.iter().map(|i| i.to_string() + "/").collect::<String>() + &name,
+    }
+}
+
+/// Returns the requested page of the entries of a real directory (or the virtual directory), how many entries there are in total,
+/// and the rendered README if it's the first page
+fn get_directory_entries(state: &AppState, figured_out_path: &FiguredOutRequestPath, path_segments: &[&str], is_data_request: bool, listing_options: &ListingOptions) -> (Vec<EntryData>, usize, Option<String>) {
+    // symlinks are left out here, rather than by get_entry_data, so pages don't come up short
+    let mut entry_paths = Vec::new();
+    if let FiguredOutRequestPath::Directory(path) = figured_out_path {
+        // todo: gray-out the folder if you can't read it
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                // the file type comes with the directory entry on most platforms, unlike the metadata, which matters with hundreds of thousands of entries
+                let Ok(file_type) = entry.file_type() else { continue };
+                if !file_type.is_symlink() {
+                    entry_paths.push((entry.path(), file_type.is_dir()));
+                }
+            }
+        }
+    } else {
+        entry_paths.extend(state.paths.iter().filter(|i| !i.is_symlink()).map(|i| (i.clone(), i.is_dir())));
+    }
+
+    // the virtual directory's entries are whatever was passed in, so a README there was meant to be shared, not shown
+    let readme = if *figured_out_path == FiguredOutRequestPath::VirtualDirectory || !listing_options.is_first_page() {
+        None
+    } else {
+        let readme_paths = entry_paths.iter().filter(|(path, _)| path.file_name().is_some_and(|i| i.to_string_lossy().to_lowercase().starts_with("readme"))).map(|(path, _)| path.clone()).collect::<Vec<_>>();
+        preview::render_readme(&readme_paths)
     };
 
-    println!("\x1b[2m{} \x1b[0;96m{}\x1b[92m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path);
-    if !is_data_request {
-        Html(DirectoryTemplate { data }.render().unwrap()).into_response()
+    let items = entry_paths.into_iter().filter_map(|(path, directory)| {
+        let name = path.file_name()?.to_string_lossy().to_string();
+        if !listing_options.matches(&name) {
+            return None
+        }
+        // sorting by name is the common case, and it doesn't need every entry to be stat'd
+        let sort_value = match listing_options.sort {
+            listing::SortKey::Name => 0.0,
+            listing::SortKey::Modified => path.metadata().ok()?.modified().map(file_metadata::seconds_since_epoch).unwrap_or(0.0),
+            listing::SortKey::Size if directory => state.directory_sizes.get(&path).unwrap_or(0) as f64,
+            listing::SortKey::Size => path.metadata().ok()?.size() as f64,
+        };
+        Some(ListingItem { name, directory, sort_value, entry: path })
+    }).collect();
+    let (page, total) = listing_options.page(items);
+
+    let entries = page.iter()
+        .filter_map(|path| get_entry_data(state, path, |name, directory| get_entry_url(path_segments, name, directory, is_data_request.then_some(state.base_path.as_str()))))
+        .collect();
+    (entries, total, readme)
+}
+
+/// Returns the listing data of a file or directory, or None for symlinks (which aren't shown)
+///
+/// `get_url` is given the entry's name and whether it's a directory
+fn get_entry_data(state: &AppState, path: &Path, get_url: impl FnOnce(&OsStr, bool) -> String) -> Option<EntryData> {
+    if path.is_symlink() {
+        return None
+    }
+    let file_name = path.file_name()?;
+    let name = file_name.to_string_lossy().to_string();
+    // the entry could've been removed since the directory was read
+    let metadata = path.metadata().ok()?;
+    let modified = metadata.modified().map(file_metadata::seconds_since_epoch).unwrap_or(0.0) as f32;
+    if metadata.is_dir() {
+        Some(EntryData {
+            url: get_url(file_name, true),
+            name,
+            directory: true,
+            size: state.directory_sizes.get(path),
+            size_computing: state.directory_sizes.is_computing(path),
+            modified,
+            thumbnail: None,
+            mime_type: None,
+            width: None,
+            height: None,
+            archive: false,
+        })
+    } else {
+        let url = get_url(file_name, false);
+        let mime_type = mime_guess::from_path(path).first_raw();
+        let dimensions = mime_type.filter(|i| i.starts_with("image/")).and_then(|_| state.thumbnails.dimensions(path, &metadata));
+        Some(EntryData {
+            thumbnail: state.thumbnails.url(path, metadata.size(), &url),
+            mime_type: mime_type.map(String::from),
+            width: dimensions.map(|i| i.0),
+            height: dimensions.map(|i| i.1),
+            archive: archive::archive_kind(path).is_some(),
+            name,
+            url,
+            directory: false,
+            size: Some(metadata.size()),
+            size_computing: false,
+            modified,
+        })
+    }
+}
+
+/// Returns the requested page of the entries of a directory inside an archive, in the same shape as a real directory's, and how many entries there are in total
+fn get_archive_entries(state: &AppState, archive_entries: Vec<archive::ArchiveEntry>, path_segments: &[&str], is_data_request: bool, listing_options: &ListingOptions) -> (Vec<EntryData>, usize) {
+    let items = archive_entries.into_iter().map(|i| ListingItem {
+        name: i.name().to_string(),
+        directory: i.directory,
+        sort_value: match listing_options.sort {
+            listing::SortKey::Name => 0.0,
+            listing::SortKey::Modified => i.modified as f64,
+            listing::SortKey::Size => i.size as f64,
+        },
+        entry: i,
+    }).collect();
+    let (page, total) = listing_options.page(items);
+    let entries = page.into_iter().map(|i| EntryData {
+        name: i.name().to_string(),
+        url: get_entry_url(path_segments, OsStr::new(i.name()), i.directory, is_data_request.then_some(state.base_path.as_str())),
+        directory: i.directory,
+        size: Some(i.size),
+        size_computing: false,
+        modified: i.modified,
+        thumbnail: None,
+        mime_type: if i.directory { None } else { mime_guess::from_path(i.name()).first_raw().map(String::from) },
+        width: None,
+        height: None,
+        archive: false,
+    }).collect();
+    (entries, total)
+}
+
+fn wrap_empty_string_in_quotation_marks<S: AsRef<str>>(string: S) -> String {
+    let string = string.as_ref().to_string();
+    if string.trim().is_empty() {
+        format!("\"{string}\"")
     } else {
-        Json(data).into_response()
+        string
     }
 }
 
+/// Returns the links shown at the top of the page, starting with the root and then each directory in the (encoded) `path_segments`
+fn get_path_components(state: &AppState, path_segments: &[&str]) -> Vec<PathComponent> {
+    let mut path_components = Vec::new();
+    path_components.push(if state.virtual_directory {
+        PathComponent { name: String::from("Virtual Directory"), url: state.base_path.clone() + "/" }
+    } else {
+        PathComponent { name: String::from("Root Directory"), url: state.base_path.clone() + "/" }
+    });
+    for (index, segment) in path_segments.iter().enumerate() {
+        path_components.push(PathComponent {
+            name: wrap_empty_string_in_quotation_marks(url_encoding::decode_lossy(segment)),
+            url: state.base_path.clone() + "/" + &path_segments[..=index].join("/") + "/"
+        });
+    }
+    path_components
+}
+
 fn get_unique_path<P>(path: &P) -> PathBuf where P: AsRef<Path> {
+    get_unique_path_where(path, |i| i.exists())
+}
+
+/// Like get_unique_path, but with something other than the filesystem deciding which paths are taken
+fn get_unique_path_where<P>(path: &P, is_taken: impl Fn(&Path) -> bool) -> PathBuf where P: AsRef<Path> {
     let path = path.as_ref().to_path_buf();
-    if !path.exists() { return path }
-    let stem = path.file_stem().unwrap().to_str().unwrap();
-    let extension = match path.extension() {
-        Some(extension) => String::from(".") + extension.to_str().unwrap(),
-        None => String::new(),
-    };
+    if !is_taken(&path) { return path }
+    // names don't have to be UTF-8, so they're put together as OsStrings
+    let stem = path.file_stem().unwrap_or_default();
+    let extension = path.extension().map(|i| [OsStr::new("."), i].into_iter().collect::<OsString>()).unwrap_or_default();
     let mut n = 1;
     loop {
-        let new_path = path.with_file_name(format!("{stem} ({n}){extension}"));
-        if !new_path.exists() { return new_path }
+        let mut name = stem.to_os_string();
+        name.push(format!(" ({n})"));
+        name.push(&extension);
+        let new_path = path.with_file_name(name);
+        if !is_taken(&new_path) { return new_path }
         n += 1;
     }
 }
 
 async fn upload_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state): State<AppState>, uri: Uri, mut multipart: Multipart) -> impl IntoResponse {
-    let request_path = uri.path();
+    let request_path = url_encoding::normalize_path(uri.path());
+    let shown_path = url_encoding::decode_lossy(&request_path);
     if !ip_authorized(&state, &addr.ip()) {
         println!("\x1b[2m{} \x1b[0;96m{}\x1b[0;2m somehow tried to upload while not being whitelisted\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
-        let template = NotWhitelistedTemplate { client_ip: addr.ip().to_string() };
+        let template = NotWhitelistedTemplate { client_ip: addr.ip().to_string(), base_path: state.base_path.clone() };
         return (
             StatusCode::UNAUTHORIZED,
             [
@@ -481,7 +1275,10 @@ async fn upload_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state)
         return StatusCode::METHOD_NOT_ALLOWED.into_response()
     }
 
-    let figured_out_request_path = figure_out_request_path(&state, request_path);
+    let figured_out_request_path = {
+        let (resolve_state, resolve_path) = (state.clone(), request_path.clone());
+        run_blocking(&state, move || figure_out_request_path(&resolve_state, resolve_path)).await
+    };
     let upload_directory = match figured_out_request_path {
         FiguredOutRequestPath::VirtualDirectory => {
             println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m tried to upload to a file somehow\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
@@ -491,48 +1288,100 @@ async fn upload_handler(ConnectInfo(addr): ConnectInfo<SocketAddr>, State(state)
             println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m tried to upload to the virtual directory somehow\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
             return StatusCode::METHOD_NOT_ALLOWED.into_response()
         },
+        FiguredOutRequestPath::ArchiveDirectory(..) | FiguredOutRequestPath::ArchiveFile(..) => {
+            println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m tried to upload into an archive\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip());
+            return StatusCode::METHOD_NOT_ALLOWED.into_response()
+        },
         FiguredOutRequestPath::NotFound => {
-            println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path);
+            println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), shown_path);
             return StatusCode::NOT_FOUND.into_response()
         },
         FiguredOutRequestPath::Directory(path) => path,
     };
 
+    match receive_uploads(&state, addr, &shown_path, &upload_directory, &mut multipart).await {
+        Ok(new_filenames) => (
+            StatusCode::CREATED,
+            [
+                (header::CONTENT_TYPE, "application/json"),
+            ],
+            serde_json::to_string(&new_filenames).unwrap()
+        ).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Saves every file in the form to `upload_directory` (renaming them unless uploads overwrite), and returns their new names
+///
+/// `request_path` is only for the log, so it's the decoded one. Files that were cut off (by the client going away, or the disk filling up)
+/// are removed, and the error is returned
+async fn receive_uploads(state: &AppState, addr: SocketAddr, request_path: &str, upload_directory: &Path, multipart: &mut Multipart) -> Result<Vec<String>, String> {
+    let result = receive_upload_files(state, addr, request_path, upload_directory, multipart).await;
+    // the filesystem watcher would probably catch this too, but it isn't available everywhere
+    state.directory_sizes.invalidate(upload_directory);
+    state.content_index.invalidate(upload_directory);
+    result
+}
+
+async fn receive_upload_files(state: &AppState, addr: SocketAddr, request_path: &str, upload_directory: &Path, multipart: &mut Multipart) -> Result<Vec<String>, String> {
     let mut new_filenames = Vec::new();
-    while let Ok(Some(mut field)) = multipart.next_field().await {
-        let filename = field.file_name().unwrap().to_string();
+    while let Some(field) = multipart.next_field().await.map_err(|e| format!("Couldn't read the upload: {e}"))? {
+        // the name comes from the client, so only its last component is used, which keeps `../` and the like from putting the file
+        // anywhere but the upload directory
+        let Some(filename) = field.file_name().and_then(|i| Path::new(i).file_name()).map(OsStr::to_os_string) else { continue };
         let path = upload_directory.join(&filename);
         let path = if !state.upload_overwrite {
             // really the unique path should be obtained when the file is
             // opened below to avoid the race condition but then I
             // wouldn't have a nice function and this is fine
-            get_unique_path(&path)
+            run_blocking(state, move || get_unique_path(&path)).await
         } else {
             path.to_path_buf()
         };
-        let new_filename = path.file_name().unwrap().to_str().unwrap();
-        new_filenames.push(new_filename.to_string());
+        let new_filename = path.file_name().unwrap().to_string_lossy().to_string();
+        new_filenames.push(new_filename.clone());
 
         println!("\x1b[2m{} \x1b[0;96m{}\x1b[95m Uploading to {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path.to_string() + &new_filename);
         
         let start_time = Instant::now();
-        let mut file = if state.upload_overwrite {
-            fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap()
-        } else {
-            fs::OpenOptions::new().write(true).create_new(true).truncate(true).open(&path).unwrap()
-        };
-        while let Some(chunk) = field.chunk().await.unwrap() {
-            file.write_all(&chunk).unwrap();
+        if let Err(e) = save_upload(state, &path, field).await {
+            println!("\x1b[2m{} \x1b[0;96m{}\x1b[91m Upload to {} failed \x1b[2m({e})\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path.to_string() + &new_filename);
+            return Err(format!("Couldn't upload {new_filename}: {e}"))
         }
         if start_time.elapsed() >= Duration::from_secs(10) {
             println!("\x1b[2m{} \x1b[0;96m{}\x1b[95m Completed upload to {}\x1b[0m", Local::now().format("%H:%M:%S"), addr.ip(), request_path.to_string() + &new_filename);
         }
     }
-    return (
-        StatusCode::CREATED,
-        [
-            (header::CONTENT_TYPE, "application/json"),
-        ],
-        serde_json::to_string(&new_filenames).unwrap()
-    ).into_response()
+    Ok(new_filenames)
+}
+
+/// Writes the file in `field` to `path`, removing it again if that fails partway
+async fn save_upload(state: &AppState, path: &Path, field: Field<'_>) -> io::Result<()> {
+    // tokio's files do their writing on the blocking thread pool, so a slow disk only holds up this upload. Each write takes one of
+    // the --fs-jobs permits like other filesystem work, but waiting for the next chunk from the client doesn't
+    let permit = filesystem_permit(state).await;
+    let mut file = if state.upload_overwrite {
+        tokio::fs::OpenOptions::new().write(true).create(true).truncate(true).open(path).await?
+    } else {
+        tokio::fs::OpenOptions::new().write(true).create_new(true).truncate(true).open(path).await?
+    };
+    drop(permit);
+    let result = write_upload(state, &mut file, field).await;
+    if result.is_err() {
+        // a file that's only partly there is worse than none, and it's only removed once it was opened above, so it's this upload's
+        // (new or truncated). Failing to open it could mean it's someone's read-only file, or another upload that got the name first
+        drop(file);
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+async fn write_upload(state: &AppState, file: &mut tokio::fs::File, mut field: Field<'_>) -> io::Result<()> {
+    while let Some(chunk) = field.chunk().await.map_err(io::Error::other)? {
+        let _permit = filesystem_permit(state).await;
+        file.write_all(&chunk).await?;
+    }
+    let _permit = filesystem_permit(state).await;
+    // otherwise the last write might still be in progress when the upload is reported as done
+    file.flush().await
 }
@@ -0,0 +1,96 @@
+use std::{fs, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use crate::{url_encoding, AppState};
+
+// depths past this get really slow on big trees, and the pages can expand directories one at a time anyway
+pub const MAX_DEPTH: usize = 8;
+
+/// A file or directory on its way into a nested listing
+pub struct WalkedEntry {
+    pub path: PathBuf,
+    pub name: String,
+    /// Already encoded, with a slash at the end for directories
+    pub url: String,
+    pub directory: bool,
+    /// Directories only have one once it's been computed
+    pub size: Option<u64>,
+    pub size_computing: bool,
+    /// None for the virtual directory
+    pub metadata: Option<fs::Metadata>,
+}
+
+/// An entry of a nested listing, like the ones `?usage=true` and `?tree=true` return, which `directory` and `virtual_directory` put together
+pub trait NestedEntry: Sized {
+    /// What choose_children needs to know, like how many children there can be
+    type Options;
+
+    /// Makes the entry, without any children
+    fn new(entry: &WalkedEntry) -> Self;
+    /// Picks which of the entries of a directory go in it, in the order they should be in
+    fn choose_children(&mut self, entries: Vec<WalkedEntry>, options: &Self::Options) -> Vec<WalkedEntry>;
+    /// Adds the children of a directory that's within the requested depth
+    fn add_children(&mut self, children: Vec<Self>);
+    /// Returns the combined size of the children (including the ones choose_children left out), or None if any of them is still being computed
+    fn children_size(&self) -> Option<u64>;
+    fn set_size(&mut self, size: u64);
+}
+
+/// Returns the listing of `path`, where `url` is the (already encoded) url of the directory, going `depth` levels down
+pub fn directory<T: NestedEntry>(state: &AppState, path: &Path, name: String, url: String, depth: usize, options: &T::Options) -> T {
+    let entry = WalkedEntry {
+        path: path.to_path_buf(),
+        name,
+        url,
+        directory: true,
+        size: state.directory_sizes.get(path),
+        size_computing: state.directory_sizes.is_computing(path),
+        metadata: path.metadata().ok(),
+    };
+    walk(state, entry, depth, options)
+}
+
+/// Returns the listing of the virtual directory, which contains every served path
+pub fn virtual_directory<T: NestedEntry>(state: &AppState, name: String, depth: usize, options: &T::Options) -> T {
+    let url = state.base_path.clone() + "/";
+    let mut listing = T::new(&WalkedEntry { path: PathBuf::new(), name, url: url.clone(), directory: true, size: None, size_computing: false, metadata: None });
+    if depth > 0 {
+        add_children(state, &mut listing, &url, state.paths.clone(), depth, options);
+        // the virtual directory doesn't have a size of its own, but its contents are all known now
+        if let Some(size) = listing.children_size() {
+            listing.set_size(size);
+        }
+    }
+    listing
+}
+
+fn walk<T: NestedEntry>(state: &AppState, entry: WalkedEntry, depth: usize, options: &T::Options) -> T {
+    let mut listing = T::new(&entry);
+    if entry.directory && depth > 0 {
+        let entry_paths = fs::read_dir(&entry.path).map(|i| i.flatten().map(|i| i.path()).collect()).unwrap_or_default();
+        add_children(state, &mut listing, &entry.url, entry_paths, depth, options);
+    }
+    listing
+}
+
+fn add_children<T: NestedEntry>(state: &AppState, listing: &mut T, url: &str, entry_paths: Vec<PathBuf>, depth: usize, options: &T::Options) {
+    let mut entries = Vec::new();
+    for path in entry_paths {
+        let Ok(metadata) = path.symlink_metadata() else { continue };
+        // symlinks are left out, like in listings
+        if metadata.is_symlink() {
+            continue;
+        }
+        let file_name = path.file_name().unwrap();
+        let directory = metadata.is_dir();
+        entries.push(WalkedEntry {
+            name: file_name.to_string_lossy().to_string(),
+            url: url.to_string() + &url_encoding::encode_name(file_name) + if directory { "/" } else { "" },
+            directory,
+            size: if directory { state.directory_sizes.get(&path) } else { Some(metadata.size()) },
+            size_computing: directory && state.directory_sizes.is_computing(&path),
+            metadata: Some(metadata),
+            path,
+        });
+    }
+    let children = listing.choose_children(entries, options).into_iter().map(|i| walk(state, i, depth - 1, options)).collect();
+    listing.add_children(children);
+}
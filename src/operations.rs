@@ -0,0 +1,67 @@
+//! What the page urls (`?data=true`, `?manifest=true` and the like) and `/api/v1` both do, so they only differ in how they're asked
+//! and how they answer. Errors are `ApiError`s, which the pages turn into just their status code and message
+
+use std::{path::Path, sync::Arc};
+use crate::{absolute_url, api::{ApiError, ErrorKind}, archive::{self, ArchiveEntry}, content_index::{self, GrepResults}, file_metadata::{self, FileMetadata}, get_search_roots, run_blocking, tree::{self, ManifestEntry}, url_encoding, AppState, FiguredOutRequestPath};
+
+/// Returns the metadata of a file or directory, including ones inside archives, with the hashes in `hash` (comma-separated)
+pub async fn metadata(state: &AppState, figured_out_path: FiguredOutRequestPath, request_path: &str, hash: Option<&str>) -> Result<FileMetadata, ApiError> {
+    let hash_algorithms = file_metadata::parse_hash_algorithms(hash.unwrap_or_default())
+        .map_err(|name| ApiError::new(ErrorKind::InvalidQuery, format!("Unknown hash algorithm: {name}")))?;
+    let url = absolute_url(state, request_path);
+    match figured_out_path {
+        FiguredOutRequestPath::File(path) | FiguredOutRequestPath::Directory(path) => {
+            run_blocking(state, move || file_metadata::file_metadata(&path, url, &hash_algorithms)).await
+                .map_err(|e| ApiError::new(ErrorKind::Io, format!("Couldn't read the metadata: {e}")))
+        },
+        FiguredOutRequestPath::ArchiveFile(archive_path, inner_path) | FiguredOutRequestPath::ArchiveDirectory(archive_path, inner_path) => {
+            let archive_entries = read_archive(state, &archive_path).await?;
+            let entry = archive::entry(&archive_entries, &inner_path)
+                .ok_or_else(|| ApiError::new(ErrorKind::NotFound, format!("{} doesn't exist", url_encoding::decode_lossy(request_path))))?;
+            let url = if entry.directory && !url.ends_with('/') { url + "/" } else { url };
+            Ok(file_metadata::archive_entry_metadata(&entry, url))
+        },
+        _ => Err(ApiError::new(ErrorKind::NotSupported, "The virtual directory doesn't have any metadata")),
+    }
+}
+
+/// Returns every file inside a directory (or the virtual directory) with its path relative to it, with the hashes in `hash`
+pub async fn manifest(state: &AppState, figured_out_path: FiguredOutRequestPath, request_path: &str, hash: Option<&str>) -> Result<Vec<ManifestEntry>, ApiError> {
+    walkable(&figured_out_path, request_path)?;
+    let hash_algorithms = file_metadata::parse_hash_algorithms(hash.unwrap_or_default())
+        .map_err(|name| ApiError::new(ErrorKind::InvalidQuery, format!("Unknown hash algorithm: {name}")))?;
+    let (manifest_state, request_path) = (state.clone(), request_path.to_string());
+    Ok(run_blocking(state, move || {
+        let roots = get_search_roots(&manifest_state, &figured_out_path, &request_path).unwrap_or_default();
+        tree::manifest(roots, &hash_algorithms)
+    }).await)
+}
+
+/// Searches the contents of the text files inside a directory (or the virtual directory)
+pub async fn grep(state: &AppState, figured_out_path: FiguredOutRequestPath, request_path: &str, query: String, limit: Option<usize>) -> Result<GrepResults, ApiError> {
+    if !state.content_index.enabled() {
+        return Err(ApiError::new(ErrorKind::ContentSearchDisabled, "Content search is turned off"))
+    }
+    walkable(&figured_out_path, request_path)?;
+    let limit = limit.unwrap_or(content_index::DEFAULT_LIMIT);
+    let (grep_state, request_path) = (state.clone(), request_path.to_string());
+    Ok(run_blocking(state, move || {
+        let roots = get_search_roots(&grep_state, &figured_out_path, &request_path).unwrap_or_default().into_iter().map(|(path, url, _)| (path, url)).collect();
+        content_index::grep(&grep_state, roots, &query, limit)
+    }).await)
+}
+
+/// Returns an error unless the path is a directory that can be walked, for searching and trees
+pub fn walkable(figured_out_path: &FiguredOutRequestPath, request_path: &str) -> Result<(), ApiError> {
+    match figured_out_path {
+        FiguredOutRequestPath::Directory(_) | FiguredOutRequestPath::VirtualDirectory => Ok(()),
+        FiguredOutRequestPath::ArchiveDirectory(..) => Err(ApiError::new(ErrorKind::NotSupported, "Only listing and stat work inside archives")),
+        _ => Err(ApiError::new(ErrorKind::NotADirectory, format!("{} is a file", url_encoding::decode_lossy(request_path)))),
+    }
+}
+
+pub async fn read_archive(state: &AppState, archive_path: &Path) -> Result<Arc<Vec<ArchiveEntry>>, ApiError> {
+    let (archive_indexes, entries_archive_path) = (state.archive_indexes.clone(), archive_path.to_path_buf());
+    run_blocking(state, move || archive_indexes.entries(&entries_archive_path)).await
+        .map_err(|e| ApiError::new(ErrorKind::UnreadableArchive, format!("Couldn't read the archive: {e}")))
+}
@@ -0,0 +1,139 @@
+use std::{fmt::Write, fs::File, io::{self, Read}, path::{Path, PathBuf}, sync::LazyLock};
+use syntect::{highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet};
+
+// highlighting is slow enough that a few hundred kB is already pushing it
+const MAX_TEXT_BYTES: u64 = 512_000;
+const MAX_HEX_BYTES: u64 = 64_000;
+// how much of the file is looked at to decide whether it's text
+const BINARY_SNIFF_BYTES: usize = 8_000;
+
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME_SET: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+/// What's shown on a file's preview page
+pub enum PreviewContent {
+    /// Highlighted (or plain, but still escaped) text
+    Code { html: String },
+    /// Rendered and sanitized Markdown
+    Markdown { html: String },
+    Image,
+    Video,
+    Audio,
+    Pdf,
+    Hex { dump: String },
+}
+
+pub struct Preview {
+    pub content: PreviewContent,
+    /// Whether only the start of the file is shown
+    pub truncated: bool,
+}
+
+/// Returns the preview of a file
+pub fn preview(path: &Path) -> io::Result<Preview> {
+    let file_size = path.metadata()?.len();
+    let mime_type = mime_guess::from_path(path).first_or_octet_stream();
+    // media is streamed by the browser itself, so there's nothing to read here
+    let media_content = match (mime_type.type_(), mime_type.subtype()) {
+        (mime::IMAGE, _) => Some(PreviewContent::Image),
+        (mime::VIDEO, _) => Some(PreviewContent::Video),
+        (mime::AUDIO, _) => Some(PreviewContent::Audio),
+        (mime::APPLICATION, mime::PDF) => Some(PreviewContent::Pdf),
+        _ => None,
+    };
+    if let Some(content) = media_content {
+        return Ok(Preview { content, truncated: false })
+    }
+
+    let mut bytes = Vec::new();
+    File::open(path)?.take(MAX_TEXT_BYTES).read_to_end(&mut bytes)?;
+    let Some(text) = as_text(&bytes) else {
+        bytes.truncate(MAX_HEX_BYTES as usize);
+        return Ok(Preview { content: PreviewContent::Hex { dump: hex_dump(&bytes) }, truncated: file_size > MAX_HEX_BYTES })
+    };
+    let truncated = file_size > MAX_TEXT_BYTES;
+
+    let extension = path.extension().map(|i| i.to_string_lossy().to_lowercase()).unwrap_or_default();
+    if ["md", "markdown"].contains(&extension.as_str()) {
+        return Ok(Preview { content: PreviewContent::Markdown { html: render_markdown(text) }, truncated })
+    }
+    Ok(Preview { content: PreviewContent::Code { html: highlight(path, text) }, truncated })
+}
+
+/// Returns the bytes as a string if they look like text, ignoring a character cut off at the end
+pub fn as_text(bytes: &[u8]) -> Option<&str> {
+    if bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0) {
+        return None
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        // error_len is None when the bytes just end partway through a character
+        Err(e) if e.error_len().is_none() => Some(std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap()),
+        Err(_) => None,
+    }
+}
+
+/// Renders Markdown to HTML, with anything that could run scripts removed
+pub fn render_markdown(text: &str) -> String {
+    let parser = pulldown_cmark::Parser::new_ext(text, pulldown_cmark::Options::ENABLE_TABLES | pulldown_cmark::Options::ENABLE_STRIKETHROUGH | pulldown_cmark::Options::ENABLE_TASKLISTS);
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    ammonia::clean(&html)
+}
+
+fn highlight(path: &Path, text: &str) -> String {
+    let syntax = SYNTAX_SET.find_syntax_for_file(path).ok().flatten()
+        .or_else(|| SYNTAX_SET.find_syntax_by_first_line(text))
+        .unwrap_or_else(|| SYNTAX_SET.find_syntax_plain_text());
+    // the light theme matches the rest of the website
+    highlighted_html_for_string(text, &SYNTAX_SET, syntax, &THEME_SET.themes["InspiredGitHub"])
+        .unwrap_or_else(|_| format!("<pre>{}</pre>", askama::filters::escape(askama::Html, text).unwrap()))
+}
+
+/// Returns an `xxd`-style dump, with the offset, 16 bytes in hex, and the bytes as ASCII on each line
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (index, line) in bytes.chunks(16).enumerate() {
+        write!(dump, "{:08x}  ", index * 16).unwrap();
+        for column in 0..16 {
+            match line.get(column) {
+                Some(byte) => write!(dump, "{byte:02x} ").unwrap(),
+                None => dump.push_str("   "),
+            }
+            if column == 7 {
+                dump.push(' ');
+            }
+        }
+        dump.push(' ');
+        dump.extend(line.iter().map(|&i| if i.is_ascii_graphic() || i == b' ' { i as char } else { '.' }));
+        dump.push('\n');
+    }
+    dump
+}
+
+/// Returns the README shown under a directory's listing, rendered to sanitized HTML
+///
+/// Markdown READMEs are preferred over plain text ones if there's both
+pub fn render_readme(entry_paths: &[PathBuf]) -> Option<String> {
+    let readme_path = ["readme.md", "readme.markdown", "readme.txt", "readme"].iter().find_map(|readme_name| {
+        entry_paths.iter().find(|i| i.is_file() && i.file_name().is_some_and(|i| i.to_string_lossy().to_lowercase() == *readme_name))
+    })?;
+    let mut bytes = Vec::new();
+    File::open(readme_path).ok()?.take(MAX_TEXT_BYTES).read_to_end(&mut bytes).ok()?;
+    let text = as_text(&bytes)?;
+    if readme_path.extension().is_some_and(|i| ["md", "markdown"].contains(&i.to_string_lossy().to_lowercase().as_str())) {
+        Some(render_markdown(text))
+    } else {
+        Some(format!("<pre>{}</pre>", askama::filters::escape(askama::Html, text).unwrap()))
+    }
+}
+
+/// Mirrors getHumanReadableFileSize in directory.js
+pub fn human_readable_size(bytes: u64) -> String {
+    for (suffix, exponent) in [("EB", 18), ("PB", 15), ("TB", 12), ("GB", 9), ("MB", 6), ("kB", 3)] {
+        if bytes >= 10u64.pow(exponent) {
+            return format!("{} {suffix}", (bytes as f64 / 10f64.powi(exponent as i32) * 100.0).floor() / 100.0)
+        }
+    }
+    format!("{bytes} B")
+}
@@ -0,0 +1,89 @@
+use std::{fs, path::PathBuf};
+use axum::body::Body;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use tokio::sync::OwnedSemaphorePermit;
+use utoipa::ToSchema;
+use crate::{get_entry_data, stream_entries, url_encoding, AppState};
+
+pub const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchMode {
+    /// The name contains the query, ignoring case
+    #[default]
+    Substring,
+    /// The name (or the path relative to the searched directory, if the pattern has a slash) matches the glob, ignoring case
+    Glob,
+}
+
+enum Matcher {
+    Substring(String),
+    Glob { matcher: GlobMatcher, match_path: bool },
+}
+
+impl Matcher {
+    fn new(query: &str, mode: SearchMode) -> Result<Self, globset::Error> {
+        Ok(match mode {
+            SearchMode::Substring => Matcher::Substring(query.to_lowercase()),
+            SearchMode::Glob => Matcher::Glob {
+                matcher: GlobBuilder::new(query).case_insensitive(true).literal_separator(true).build()?.compile_matcher(),
+                match_path: query.contains('/'),
+            },
+        })
+    }
+
+    fn matches(&self, name: &str, relative_path: &str) -> bool {
+        match self {
+            Matcher::Substring(query) => name.to_lowercase().contains(query),
+            Matcher::Glob { matcher, match_path: true } => matcher.is_match(relative_path),
+            Matcher::Glob { matcher, match_path: false } => matcher.is_match(name),
+        }
+    }
+}
+
+/// Searches everything inside `roots` and streams the matches as newline-delimited `EntryData` JSON, stopping after `limit` matches
+///
+/// Each root is its path, its already encoded url, and its path relative to where the search started,
+/// which is empty for the directory being searched (so it isn't matched itself). Symlinks are skipped like they are in listings
+pub fn search(state: AppState, permit: OwnedSemaphorePermit, roots: Vec<(PathBuf, String, String)>, query: &str, mode: SearchMode, limit: usize) -> Result<Body, globset::Error> {
+    let matcher = Matcher::new(query, mode)?;
+    let entries_state = state.clone();
+    // walking a big tree takes a while, so the matches are streamed as they're found
+    Ok(stream_entries(state, permit, move || {
+        // (path, url, path relative to the root it's in)
+        let mut stack = roots;
+        let matches = std::iter::from_fn(move || {
+            while let Some((path, url, relative_path)) = stack.pop() {
+                if path.is_symlink() {
+                    continue;
+                }
+                if let Ok(read_dir) = fs::read_dir(&path) {
+                    let mut children = read_dir.flatten().collect::<Vec<_>>();
+                    // reversed so they come off the stack in order, after the directory itself
+                    children.sort_unstable_by_key(|i| std::cmp::Reverse(i.file_name()));
+                    for child in children {
+                        let Ok(file_type) = child.file_type() else { continue };
+                        let name = child.file_name().to_string_lossy().to_string();
+                        let child_url = url.clone() + &url_encoding::encode_name(child.file_name()) + if file_type.is_dir() { "/" } else { "" };
+                        let child_relative_path = if relative_path.is_empty() { name } else { relative_path.clone() + "/" + &name };
+                        stack.push((child.path(), child_url, child_relative_path));
+                    }
+                }
+                if relative_path.is_empty() {
+                    continue;
+                }
+                let name = path.file_name().map(|i| i.to_string_lossy().to_string()).unwrap_or_default();
+                if matcher.matches(&name, &relative_path) {
+                    if let Some(entry_data) = get_entry_data(&entries_state, &path, |_, _| url.clone()) {
+                        return Some(entry_data)
+                    }
+                }
+            }
+            None
+        });
+        // nothing is walked for a limit of 0
+        matches.take(limit)
+    }))
+}
@@ -0,0 +1,317 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs, os::unix::fs::MetadataExt, path::{Component, Path}};
+use reqwest::{multipart::Form, Client, Url};
+use serde::{Deserialize, Serialize};
+use crate::{cli::RemoteUrl, client::{self, RemoteFile, RemoteMetadata}, file_metadata::{self, HashAlgorithm}, get_unique_path_where, preview::human_readable_size};
+
+/// Kept in the synced directory, and never synced itself
+const STATE_FILE_NAME: &str = ".gshare3-sync.json";
+
+/// What a file was on both sides after it was last synced, which is what tells a change on one side apart from a change on the other
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SyncedFile {
+    size: u64,
+    /// Seconds since the unix epoch, the mtimes differ between the sides since they're set when a file is written
+    local_modified: f64,
+    remote_modified: f64,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SyncState {
+    /// The directory the files were synced with, the state is ignored when syncing with another one
+    url: String,
+    /// By path relative to the synced directory, separated by slashes
+    files: BTreeMap<String, SyncedFile>,
+}
+
+struct LocalFile {
+    size: u64,
+    modified: f64,
+}
+
+#[derive(Default)]
+struct Summary {
+    uploaded: usize,
+    downloaded: usize,
+    transferred_size: u64,
+    conflicts: usize,
+    up_to_date: usize,
+}
+
+/// Syncs `directory` with the directory at `url` both ways
+///
+/// Files that only changed on one side since the last sync are copied over to the other, and files that changed on both keep the
+/// server's version next to the local one, named like uploads that would overwrite something are. Deleted files aren't deleted on the
+/// other side, since the server can't delete anything
+pub async fn sync(client: &Client, directory: &Path, remote: &RemoteUrl) -> Result<(), String> {
+    let (mut url, base_path) = client::resolve_url(client, remote).await?;
+    // relative paths are joined onto it, so it has to be a directory's url
+    if !url.path().ends_with('/') {
+        url.set_path(&(url.path().to_string() + "/"));
+    }
+    fs::create_dir_all(directory).map_err(|e| format!("Couldn't create {}: {e}", directory.display()))?;
+    let state_path = directory.join(STATE_FILE_NAME);
+    let previous = fs::read(&state_path).ok()
+        .and_then(|i| serde_json::from_slice::<SyncState>(&i).ok())
+        .filter(|i| i.url == url.as_str())
+        .unwrap_or_default();
+
+    let manifest: Vec<RemoteFile> = client::get_json(client, client::api_url(&url, &base_path, "manifest")?).await?;
+    let mut remote_files = BTreeMap::new();
+    for file in manifest {
+        // the paths come from the server, so they're kept from going anywhere outside the directory
+        if !Path::new(&file.path).components().all(|i| matches!(i, Component::Normal(_))) {
+            eprintln!("\x1b[93mSkipping {}, it isn't a relative path\x1b[0m", file.path);
+            continue;
+        }
+        if file.path != STATE_FILE_NAME {
+            remote_files.insert(file.path.clone(), file);
+        }
+    }
+    let mut local_files = local_files(directory);
+    // downloads that were cut off, which get continued instead of uploaded
+    local_files.retain(|path, _| !path.strip_suffix(".part").is_some_and(|i| remote_files.contains_key(i)));
+
+    // everything is compared before anything is copied, so a server that can't take the uploads is found out before anything changed
+    let paths = local_files.keys().chain(remote_files.keys()).chain(previous.files.keys()).cloned().collect::<BTreeSet<_>>();
+    let mut changes = Vec::new();
+    let mut synced = BTreeMap::new();
+    let mut summary = Summary::default();
+    for path in paths {
+        let (local, remote, base) = (local_files.get(&path), remote_files.get(&path), previous.files.get(&path));
+        let local_hash = match local {
+            Some(local) => Some(local_hash(&directory.join(&path), local, base)?),
+            None => None,
+        };
+        let remote_hash = match remote {
+            Some(remote) => Some(remote_hash(client, &url, &base_path, remote, base).await?),
+            None => None,
+        };
+        let action = action(local_hash.as_deref(), remote_hash.as_deref(), base.map(|i| i.sha256.as_str()));
+        if action == Action::UpToDate {
+            if let (Some(local), Some(remote), Some(sha256)) = (local, remote, local_hash) {
+                synced.insert(path, SyncedFile { size: local.size, local_modified: local.modified, remote_modified: remote.modified, sha256 });
+                summary.up_to_date += 1;
+            }
+        } else {
+            changes.push((path, action, local_hash, remote_hash));
+        }
+    }
+    if changes.iter().any(|(_, action, _, _)| matches!(action, Action::Upload | Action::Conflict)) {
+        check_overwriting(client, &url, &base_path).await?;
+    }
+
+    for (path, action, local_hash, remote_hash) in changes {
+        let (local, remote, base) = (local_files.get(&path), remote_files.get(&path), previous.files.get(&path));
+        let local_path = directory.join(&path);
+        match action {
+            Action::Conflict => {
+                let (local, remote) = (local.unwrap(), remote.unwrap());
+                // the server's version is downloaded next to the local one, then both are uploaded so both sides end up with both
+                let conflict_path = get_unique_path_where(&local_path, |i| i.exists() || remote_files.contains_key(&relative_path(directory, i)));
+                let conflict_relative_path = relative_path(directory, &conflict_path);
+                println!("\x1b[93m{path} changed on both sides, the server's version is saved as {conflict_relative_path}\x1b[0m");
+                let conflict_file = download(client, &url, remote, &conflict_path, &conflict_relative_path, remote_hash.unwrap()).await?;
+                summary.downloaded += 1;
+                summary.transferred_size += remote.size;
+                if let Some(synced_file) = upload(client, &url, &base_path, &local_path, &path, local, local_hash.unwrap()).await? {
+                    synced.insert(path.clone(), synced_file);
+                }
+                summary.uploaded += 1;
+                summary.transferred_size += local.size;
+                let conflict_local = LocalFile { size: conflict_file.size, modified: conflict_file.local_modified };
+                if let Some(synced_file) = upload(client, &url, &base_path, &conflict_path, &conflict_relative_path, &conflict_local, conflict_file.sha256).await? {
+                    synced.insert(conflict_relative_path, synced_file);
+                }
+                summary.uploaded += 1;
+                summary.transferred_size += conflict_local.size;
+                summary.conflicts += 1;
+            },
+            Action::Upload => {
+                let local = local.unwrap();
+                if let Some(synced_file) = upload(client, &url, &base_path, &local_path, &path, local, local_hash.unwrap()).await? {
+                    synced.insert(path, synced_file);
+                }
+                summary.uploaded += 1;
+                summary.transferred_size += local.size;
+            },
+            Action::Download => {
+                let remote = remote.unwrap();
+                if let Some(parent) = local_path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| format!("Couldn't create {}: {e}", parent.display()))?;
+                }
+                synced.insert(path.clone(), download(client, &url, remote, &local_path, &path, remote_hash.unwrap()).await?);
+                summary.downloaded += 1;
+                summary.transferred_size += remote.size;
+            },
+            Action::Deleted => {
+                // the deleted side is remembered, so it's reported again instead of the file being copied back
+                if local.is_none() {
+                    println!("\x1b[2m{path} was deleted here but is still on the server\x1b[0m");
+                } else {
+                    println!("\x1b[2m{path} was deleted on the server but is still here\x1b[0m");
+                }
+                if let Some(base) = base {
+                    synced.insert(path, base.clone());
+                }
+            },
+            Action::UpToDate => unreachable!(),
+        }
+    }
+
+    let state = SyncState { url: url.to_string(), files: synced };
+    fs::write(&state_path, serde_json::to_vec(&state).unwrap()).map_err(|e| format!("Couldn't save {}: {e}", state_path.display()))?;
+    println!(
+        "Uploaded \x1b[96m{}\x1b[0m and downloaded \x1b[96m{}\x1b[0m files ({}), \x1b[96m{}\x1b[0m conflicts, \x1b[96m{}\x1b[0m already up to date",
+        summary.uploaded, summary.downloaded, human_readable_size(summary.transferred_size), summary.conflicts, summary.up_to_date,
+    );
+    Ok(())
+}
+
+/// What has to be done with a path to sync it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Both sides have the same file, or neither has one
+    UpToDate,
+    Upload,
+    Download,
+    /// It changed on both sides, so both versions end up on both
+    Conflict,
+    /// It was deleted on the side that didn't change otherwise, which isn't copied over
+    Deleted,
+}
+
+/// Decides what to do with a path from the hashes of the local file, the server's file, and the file after the last sync (None where
+/// there isn't one)
+fn action(local_hash: Option<&str>, remote_hash: Option<&str>, base_hash: Option<&str>) -> Action {
+    if local_hash == remote_hash {
+        return Action::UpToDate
+    }
+    // a side that's still like it was after the last sync didn't change, so the other side's version wins
+    let (upload_local, download_remote) = if remote_hash == base_hash {
+        (local_hash.is_some(), false)
+    } else if local_hash == base_hash {
+        (false, remote_hash.is_some())
+    } else {
+        (local_hash.is_some(), remote_hash.is_some())
+    };
+    match (upload_local, download_remote) {
+        (true, true) => Action::Conflict,
+        (true, false) => Action::Upload,
+        (false, true) => Action::Download,
+        (false, false) => Action::Deleted,
+    }
+}
+
+/// Uploads nothing to the directory at `url` asking for files to be overwritten, which fails if the server renames uploads instead
+/// (or doesn't take any). Fields without a file name are skipped, and there has to be one for the form to be read
+async fn check_overwriting(client: &Client, url: &Url, base_path: &str) -> Result<(), String> {
+    let mut upload_url = client::api_url(url, base_path, "upload")?;
+    upload_url.set_query(Some("overwrite=true&create_directories=true"));
+    let response = client.post(upload_url).multipart(Form::new().text("check", "")).send().await.map_err(|e| e.to_string())?;
+    client::check(response).await.map(|_| ())
+}
+
+/// Returns every file inside `directory` by their paths relative to it, leaving out symlinks like the server does
+fn local_files(directory: &Path) -> BTreeMap<String, LocalFile> {
+    let mut files = BTreeMap::new();
+    let mut stack = vec![directory.to_path_buf()];
+    while let Some(path) = stack.pop() {
+        let Ok(read_dir) = fs::read_dir(&path) else { continue };
+        for child in read_dir.flatten() {
+            let Ok(metadata) = child.path().symlink_metadata() else { continue };
+            if metadata.is_dir() {
+                stack.push(child.path());
+            } else if metadata.is_file() {
+                let relative_path = relative_path(directory, &child.path());
+                if relative_path != STATE_FILE_NAME {
+                    files.insert(relative_path, LocalFile { size: metadata.size(), modified: modified(&metadata) });
+                }
+            }
+        }
+    }
+    files
+}
+
+fn relative_path(directory: &Path, path: &Path) -> String {
+    path.strip_prefix(directory).unwrap().components().map(|i| i.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+fn modified(metadata: &fs::Metadata) -> f64 {
+    metadata.mtime() as f64 + metadata.mtime_nsec() as f64 / 1e9
+}
+
+/// Returns the hash of a local file, which is only read if it changed since the last sync
+fn local_hash(path: &Path, local: &LocalFile, base: Option<&SyncedFile>) -> Result<String, String> {
+    if let Some(base) = base.filter(|i| i.size == local.size && i.local_modified == local.modified) {
+        return Ok(base.sha256.clone())
+    }
+    let mut hashes = file_metadata::hashes(path, &[HashAlgorithm::Sha256]).map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+    Ok(hashes.remove("sha256").unwrap())
+}
+
+/// Returns the hash of a file on the server, which is only asked for if it changed since the last sync
+async fn remote_hash(client: &Client, url: &Url, base_path: &str, remote: &RemoteFile, base: Option<&SyncedFile>) -> Result<String, String> {
+    if let Some(base) = base.filter(|i| i.size == remote.size && i.remote_modified == remote.modified) {
+        return Ok(base.sha256.clone())
+    }
+    let mut stat_url = client::api_url(&url.join(&remote.url).map_err(|e| e.to_string())?, base_path, "stat")?;
+    stat_url.set_query(Some("hash=sha256"));
+    let mut metadata: RemoteMetadata = client::get_json(client, stat_url).await?;
+    metadata.hashes.remove("sha256").ok_or_else(|| format!("The server didn't hash {}", remote.path))
+}
+
+async fn download(client: &Client, url: &Url, remote: &RemoteFile, path: &Path, label: &str, sha256: String) -> Result<SyncedFile, String> {
+    let file_url = url.join(&remote.url).map_err(|e| e.to_string())?;
+    client::download(client, file_url, path, remote.size, remote.modified, label.to_string()).await?;
+    let metadata = fs::metadata(path).map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+    Ok(SyncedFile { size: remote.size, local_modified: modified(&metadata), remote_modified: remote.modified, sha256 })
+}
+
+/// Uploads the file at `path` to `relative_path` on the server, creating the directories it's in
+///
+/// Returns None if the server saved it under another name, which is remembered as the server having a file that isn't here
+async fn upload(client: &Client, url: &Url, base_path: &str, path: &Path, relative_path: &str, local: &LocalFile, sha256: String) -> Result<Option<SyncedFile>, String> {
+    let mut directory_url = url.clone();
+    let (parent, name) = relative_path.rsplit_once('/').unwrap_or(("", relative_path));
+    {
+        let mut segments = directory_url.path_segments_mut().map_err(|_| format!("Invalid url {url}"))?;
+        segments.pop_if_empty();
+        segments.extend(parent.split('/').filter(|i| !i.is_empty()));
+        segments.push("");
+    }
+    let mut upload_url = client::api_url(&directory_url, base_path, "upload")?;
+    upload_url.set_query(Some("overwrite=true&create_directories=true"));
+    let saved_name = client::upload(client, upload_url, path, relative_path.to_string()).await?;
+    if saved_name != name {
+        eprintln!("\x1b[93mThe server saved {relative_path} as {saved_name} instead of replacing it, it has to allow overwriting files (--upload-overwrite)\x1b[0m");
+        return Ok(None)
+    }
+    let mut file_url = directory_url.clone();
+    file_url.path_segments_mut().unwrap().pop_if_empty().push(&saved_name);
+    let metadata: RemoteMetadata = client::get_json(client, client::api_url(&file_url, base_path, "stat")?).await?;
+    Ok(Some(SyncedFile { size: local.size, local_modified: local.modified, remote_modified: metadata.modified, sha256 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_side_that_changed_wins() {
+        let (a, b, c) = (Some("a"), Some("b"), Some("c"));
+        assert_eq!(action(a, a, b), Action::UpToDate);
+        assert_eq!(action(None, None, a), Action::UpToDate);
+        assert_eq!(action(b, a, a), Action::Upload);
+        assert_eq!(action(a, None, None), Action::Upload);
+        assert_eq!(action(a, b, a), Action::Download);
+        assert_eq!(action(None, a, None), Action::Download);
+        assert_eq!(action(b, c, a), Action::Conflict);
+        assert_eq!(action(a, b, None), Action::Conflict);
+        assert_eq!(action(None, a, a), Action::Deleted);
+        assert_eq!(action(a, None, a), Action::Deleted);
+        // deleted on one side and changed on the other, so the changed one is copied back
+        assert_eq!(action(None, b, a), Action::Download);
+        assert_eq!(action(b, None, a), Action::Upload);
+    }
+}
@@ -0,0 +1,37 @@
+use std::{fs, ops::Deref, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+
+/// Tells apart the directories of tests running at the same time in this process
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory for a unit test to write its files in, removed (with everything in it) when it's dropped, even if the
+/// test fails
+pub struct TemporaryDirectory(PathBuf);
+
+impl TemporaryDirectory {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gshare3-{name}-{}-{}", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TemporaryDirectory {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TemporaryDirectory {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TemporaryDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{cmp::Reverse, fs, io::{Cursor, Read}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+use image::{imageops::FilterType, metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use sha2::{Digest, Sha256};
+use crate::versioned_cache::{file_version, VersionedCache};
+
+pub const DEFAULT_SIZE: u32 = 256;
+// anything bigger than this is basically just the image, and it keeps people from filling up the cache with every size imaginable
+const MAX_SIZE: u32 = 1024;
+const MIN_SIZE: u32 = 16;
+// decoding huge images takes ages and a lot of memory, those get the generic icon
+const MAX_FILE_SIZE: u64 = 64_000_000;
+// the cache is kept under this by removing the thumbnails that were used the longest ago
+const MAX_CACHE_SIZE: u64 = 256_000_000;
+// and trimmed down to this when it's over, so it isn't trimmed again with every new thumbnail after
+const TRIMMED_CACHE_SIZE: u64 = MAX_CACHE_SIZE / 10 * 9;
+// listing a huge photo collection would otherwise keep the dimensions of every image in it forever
+const MAX_CACHED_DIMENSIONS: usize = 100_000;
+
+/// Generates thumbnails for image files, and caches them on disk if there's a cache directory
+#[derive(Debug, Clone)]
+pub struct Thumbnails {
+    enabled: bool,
+    cache_directory: Option<PathBuf>,
+    /// How much is in the cache directory, which isn't known until the first thumbnail is cached
+    cache_size: Arc<Mutex<Option<u64>>>,
+    /// The dimensions of images that have been listed or had a thumbnail made, None if they couldn't be read
+    dimensions: VersionedCache<Option<(u32, u32)>>,
+}
+
+/// A generated (or cached) thumbnail
+pub struct Thumbnail {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+impl Thumbnails {
+    pub fn new(enabled: bool, cache_directory: Option<PathBuf>) -> Self {
+        Self { enabled, cache_directory, cache_size: Arc::default(), dimensions: VersionedCache::new(MAX_CACHED_DIMENSIONS) }
+    }
+
+    /// Returns whether a thumbnail can (probably) be made for the file, only looks at the extension and size
+    pub fn available<P: AsRef<Path>>(&self, path: P, file_size: u64) -> bool {
+        self.enabled && file_size <= MAX_FILE_SIZE && ImageFormat::from_path(path).is_ok_and(|i| i.reading_enabled())
+    }
+
+    /// Returns the thumbnail url for a file, where `url` is the file's url
+    pub fn url<P: AsRef<Path>>(&self, path: P, file_size: u64, url: &str) -> Option<String> {
+        self.available(path, file_size).then(|| format!("{url}?thumb={DEFAULT_SIZE}"))
+    }
+
+    /// Returns a thumbnail that fits in a `size`x`size` square
+    pub fn get(&self, path: &Path, size: u32) -> Option<Thumbnail> {
+        let size = size.clamp(MIN_SIZE, MAX_SIZE);
+        let metadata = path.metadata().ok()?;
+        if !self.available(path, metadata.size()) {
+            return None
+        }
+
+        // the path, mtime and size are all part of the name, so a changed file never gets an outdated thumbnail (and "oriented" keeps
+        // the ones from before the EXIF orientation was applied from being used)
+        let cache_key = format!("{}\0{}.{}\0{}\0{size}\0oriented", path.to_string_lossy(), metadata.mtime(), metadata.mtime_nsec(), metadata.size());
+        let cache_name = Sha256::digest(cache_key.as_bytes()).iter().map(|i| format!("{i:02x}")).collect::<String>();
+        if let Some(cache_directory) = &self.cache_directory {
+            for (extension, content_type) in [("jpg", "image/jpeg"), ("png", "image/png")] {
+                let Ok(mut file) = fs::File::open(cache_directory.join(format!("{cache_name}.{extension}"))) else { continue };
+                let mut bytes = Vec::new();
+                if file.read_to_end(&mut bytes).is_ok() {
+                    // the cache is trimmed by modification time, so this one counts as just used
+                    let _ = file.set_modified(SystemTime::now());
+                    return Some(Thumbnail { bytes, content_type })
+                }
+            }
+        }
+
+        let image = open_oriented(path);
+        // they're right there, so listings don't have to read them again
+        self.dimensions.insert(path, file_version(&metadata), image.as_ref().map(|i| (i.width(), i.height())));
+        let image = image?.resize(size, size, FilterType::Triangle);
+        let mut bytes = Vec::new();
+        // jpeg doesn't do transparency, and png is way too big for photos
+        let (format, extension, content_type) = if image.color().has_alpha() {
+            (ImageFormat::Png, "png", "image/png")
+        } else {
+            (ImageFormat::Jpeg, "jpg", "image/jpeg")
+        };
+        let image = if format == ImageFormat::Jpeg { image.to_rgb8().into() } else { image };
+        image.write_to(&mut Cursor::new(&mut bytes), format).ok()?;
+
+        if let Some(cache_directory) = &self.cache_directory {
+            // written to a separate file first so another request never reads a half-written thumbnail
+            let temporary_path = cache_directory.join(format!("{cache_name}.{extension}.tmp"));
+            let result = fs::create_dir_all(cache_directory)
+                .and_then(|_| fs::write(&temporary_path, &bytes))
+                .and_then(|_| fs::rename(&temporary_path, cache_directory.join(format!("{cache_name}.{extension}"))));
+            // the thumbnail still works without the cache, so this isn't worth failing over
+            match result {
+                Ok(()) => self.add_to_cache(cache_directory, bytes.len() as u64),
+                Err(e) => eprintln!("\x1b[91mCouldn't cache thumbnail in {}: {e}\x1b[0m", cache_directory.to_string_lossy()),
+            }
+        }
+        Some(Thumbnail { bytes, content_type })
+    }
+
+    /// Returns the width and height of an image as it's shown (turned the way its EXIF orientation says), reading just its header the
+    /// first time
+    pub fn dimensions(&self, path: &Path, metadata: &fs::Metadata) -> Option<(u32, u32)> {
+        let version = file_version(metadata);
+        if let Some(dimensions) = self.dimensions.get(path, version) {
+            return dimensions
+        }
+        let dimensions = read_dimensions(path);
+        self.dimensions.insert(path, version, dimensions);
+        dimensions
+    }
+
+    /// Counts a new thumbnail towards the size of the cache, and trims it if that's too much now
+    fn add_to_cache(&self, cache_directory: &Path, size: u64) {
+        let mut cache_size = self.cache_size.lock().unwrap();
+        // thumbnails from before this run count too, and were only looked at once
+        let new_size = match *cache_size {
+            Some(cache_size) => cache_size + size,
+            None => cached_files(cache_directory).iter().map(|(_, size, _)| size).sum(),
+        };
+        *cache_size = Some(if new_size > MAX_CACHE_SIZE { trim_cache(cache_directory) } else { new_size });
+    }
+}
+
+/// Opens an image, turned the way its EXIF orientation says, since that's how anything that shows photos shows them
+fn open_oriented(path: &Path) -> Option<DynamicImage> {
+    let mut decoder = ImageReader::open(path).ok()?.with_guessed_format().ok()?.into_decoder().ok()?;
+    let orientation = decoder.orientation().ok();
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    if let Some(orientation) = orientation {
+        image.apply_orientation(orientation);
+    }
+    Some(image)
+}
+
+/// Reads the dimensions of an image from its header, swapped if its EXIF orientation turns it sideways
+fn read_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let mut decoder = ImageReader::open(path).ok()?.with_guessed_format().ok()?.into_decoder().ok()?;
+    let (width, height) = decoder.dimensions();
+    Some(match decoder.orientation() {
+        Ok(Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH) => (height, width),
+        _ => (width, height),
+    })
+}
+
+/// Returns the thumbnails in the cache with their sizes and when they were last used
+fn cached_files(cache_directory: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(read_dir) = fs::read_dir(cache_directory) else { return Vec::new() };
+    read_dir.flatten()
+        // thumbnails that are still being written are left alone
+        .filter(|i| i.path().extension().is_some_and(|i| i != "tmp"))
+        .filter_map(|i| i.metadata().ok().filter(|i| i.is_file()).map(|metadata| (i.path(), metadata.size(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH))))
+        .collect()
+}
+
+/// Removes the thumbnails that were used the longest ago until the cache is down to TRIMMED_CACHE_SIZE, and returns its size after
+fn trim_cache(cache_directory: &Path) -> u64 {
+    let mut files = cached_files(cache_directory);
+    files.sort_unstable_by_key(|(_, _, modified)| Reverse(*modified));
+    let (mut kept_size, mut trimming) = (0, false);
+    for (path, size, _) in files {
+        // everything older than the first one that doesn't fit goes, even if it would
+        trimming |= kept_size + size > TRIMMED_CACHE_SIZE;
+        if !trimming {
+            kept_size += size;
+        } else if let Err(e) = fs::remove_file(&path) {
+            eprintln!("\x1b[91mCouldn't remove {} from the thumbnail cache: {e}\x1b[0m", path.to_string_lossy());
+            kept_size += size;
+        }
+    }
+    kept_size
+}
+
+/// Returns the default location of the thumbnail cache, if the platform has a cache directory
+pub fn default_cache_directory() -> Option<PathBuf> {
+    dirs::cache_dir().map(|i| i.join("gshare3").join("thumbnails"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use image::{codecs::jpeg::JpegEncoder, ExtendedColorType, ImageEncoder};
+    use crate::temporary_directory::TemporaryDirectory;
+    use super::*;
+
+    /// Writes a `width`x`height` JPEG with an EXIF orientation of `orientation`
+    fn write_jpeg(path: &Path, width: u32, height: u32, orientation: u16) {
+        // a big endian TIFF header with a single IFD holding only the orientation
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut encoder = JpegEncoder::new(fs::File::create(path).unwrap());
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder.write_image(&vec![128; (width * height * 3) as usize], width, height, ExtendedColorType::Rgb8).unwrap();
+    }
+
+    #[test]
+    fn sideways_orientations_swap_the_dimensions() {
+        let directory = TemporaryDirectory::new("thumbnails");
+        let path = directory.join("orientation.jpg");
+        for (orientation, dimensions) in [(1, (40, 20)), (3, (40, 20)), (6, (20, 40)), (8, (20, 40))] {
+            write_jpeg(&path, 40, 20, orientation);
+            assert_eq!(read_dimensions(&path), Some(dimensions), "{orientation}");
+            let thumbnail = Thumbnails::new(true, None).get(&path, MIN_SIZE).unwrap();
+            let thumbnail = image::load_from_memory(&thumbnail.bytes).unwrap();
+            assert_eq!(thumbnail.width() > thumbnail.height(), dimensions.0 > dimensions.1, "{orientation}");
+        }
+    }
+
+    #[test]
+    fn dimensions_are_read_again_when_the_file_changes() {
+        let directory = TemporaryDirectory::new("thumbnails");
+        let path = directory.join("dimensions.jpg");
+        let thumbnails = Thumbnails::new(true, None);
+        write_jpeg(&path, 40, 20, 1);
+        assert_eq!(thumbnails.dimensions(&path, &path.metadata().unwrap()), Some((40, 20)));
+        write_jpeg(&path, 40, 30, 6);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(thumbnails.dimensions(&path, &path.metadata().unwrap()), Some((30, 40)));
+    }
+
+    #[test]
+    fn changed_files_get_new_thumbnails() {
+        let directory = TemporaryDirectory::new("thumbnails");
+        let (path, cache_directory) = (directory.join("cached.jpg"), directory.join("cache"));
+        let thumbnails = Thumbnails::new(true, Some(cache_directory.clone()));
+        let cached_count = || fs::read_dir(&cache_directory).unwrap().count();
+        write_jpeg(&path, 40, 20, 1);
+        let first = thumbnails.get(&path, MIN_SIZE).unwrap().bytes;
+        assert_eq!(thumbnails.get(&path, MIN_SIZE).unwrap().bytes, first);
+        assert_eq!(cached_count(), 1);
+        // a different size is a different thumbnail
+        thumbnails.get(&path, MIN_SIZE * 2).unwrap();
+        assert_eq!(cached_count(), 2);
+        write_jpeg(&path, 20, 40, 1);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert_ne!(thumbnails.get(&path, MIN_SIZE).unwrap().bytes, first);
+        assert_eq!(cached_count(), 3);
+    }
+
+    #[test]
+    fn trimming_removes_the_thumbnails_used_longest_ago() {
+        let cache_directory = TemporaryDirectory::new("thumbnails");
+        // sparse, so they take up no space but count as MAX_CACHE_SIZE / 3 each
+        let now = SystemTime::now();
+        for (index, name) in ["newest.jpg", "newer.png", "older.jpg", "oldest.jpg", "in-progress.jpg.tmp"].into_iter().enumerate() {
+            let file = fs::File::create(cache_directory.join(name)).unwrap();
+            file.set_len(MAX_CACHE_SIZE / 3).unwrap();
+            file.set_modified(now - Duration::from_secs(60 * index as u64)).unwrap();
+        }
+        assert_eq!(trim_cache(&cache_directory), MAX_CACHE_SIZE / 3 * 2);
+        let mut left = fs::read_dir(&cache_directory).unwrap().map(|i| i.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["in-progress.jpg.tmp", "newer.png", "newest.jpg"]);
+    }
+}
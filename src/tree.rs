@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, fs, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::{file_metadata::{self, HashAlgorithm}, nested_listing::{self, NestedEntry, WalkedEntry}, url_encoding, AppState};
+
+/// A file or directory in the nested listing returned by `?tree=true`
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TreeEntry {
+    name: String,
+    url: String,
+    directory: bool,
+    size: Option<u64>,
+    size_computing: bool,
+    /// Seconds since the unix epoch, None for the virtual directory
+    modified: Option<f64>,
+    /// Sorted by name
+    ///
+    /// Empty for files and for directories past the requested depth, check `expanded` to tell those apart from empty directories
+    #[schema(no_recursion)]
+    children: Vec<TreeEntry>,
+    expanded: bool,
+}
+
+/// A file in the flat listing returned by `?manifest=true`
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ManifestEntry {
+    /// Relative to the directory the manifest is of, separated by slashes
+    path: String,
+    url: String,
+    size: u64,
+    /// Seconds since the unix epoch
+    modified: f64,
+    /// The hashes asked for with `?hash=sha256`, as lowercase hex
+    hashes: BTreeMap<&'static str, String>,
+}
+
+impl NestedEntry for TreeEntry {
+    type Options = ();
+
+    fn new(entry: &WalkedEntry) -> Self {
+        TreeEntry {
+            name: entry.name.clone(),
+            url: entry.url.clone(),
+            directory: entry.directory,
+            size: entry.size,
+            size_computing: entry.size_computing,
+            modified: entry.metadata.as_ref().map(|i| i.mtime() as f64 + i.mtime_nsec() as f64 / 1e9),
+            children: Vec::new(),
+            expanded: false,
+        }
+    }
+
+    fn choose_children(&mut self, mut entries: Vec<WalkedEntry>, _: &()) -> Vec<WalkedEntry> {
+        entries.sort_unstable_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+        entries
+    }
+
+    fn add_children(&mut self, children: Vec<Self>) {
+        self.children = children;
+        self.expanded = true;
+    }
+
+    fn children_size(&self) -> Option<u64> {
+        let computing = self.children.iter().any(|i| i.size_computing);
+        (!computing).then(|| self.children.iter().filter_map(|i| i.size).sum())
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.size = Some(size);
+    }
+}
+
+/// Returns the tree of `path`, where `url` is the (already encoded) url of the directory, going `depth` levels down, or
+/// nested_listing::MAX_DEPTH without one (which is also the most it goes)
+pub fn directory_tree(state: &AppState, path: &Path, name: String, url: String, depth: Option<usize>) -> TreeEntry {
+    nested_listing::directory(state, path, name, url, clamp_depth(depth), &())
+}
+
+/// Returns the tree of the virtual directory, which contains every served path
+pub fn virtual_directory_tree(state: &AppState, name: String, depth: Option<usize>) -> TreeEntry {
+    nested_listing::virtual_directory(state, name, clamp_depth(depth), &())
+}
+
+fn clamp_depth(depth: Option<usize>) -> usize {
+    depth.unwrap_or(nested_listing::MAX_DEPTH).min(nested_listing::MAX_DEPTH)
+}
+
+/// Returns every file inside `roots`, sorted by path, reading every file if there's anything to hash
+///
+/// The roots are like the ones `?search=` takes: their paths, their already encoded urls, and their paths relative to where the manifest is of.
+/// Files that can't be read are left out, since they couldn't be downloaded anyway
+pub fn manifest(roots: Vec<(PathBuf, String, String)>, hash_algorithms: &[HashAlgorithm]) -> Vec<ManifestEntry> {
+    let mut files = Vec::new();
+    let mut stack = roots;
+    while let Some((path, url, relative_path)) = stack.pop() {
+        let Ok(metadata) = path.symlink_metadata() else { continue };
+        if metadata.is_symlink() {
+            continue;
+        }
+        if metadata.is_file() {
+            let Ok(hashes) = file_metadata::hashes(&path, hash_algorithms) else { continue };
+            files.push(ManifestEntry {
+                path: relative_path,
+                url,
+                size: metadata.size(),
+                modified: metadata.mtime() as f64 + metadata.mtime_nsec() as f64 / 1e9,
+                hashes,
+            });
+            continue;
+        }
+        let Ok(read_dir) = fs::read_dir(&path) else { continue };
+        for child in read_dir.flatten() {
+            let Ok(file_type) = child.file_type() else { continue };
+            let name = child.file_name().to_string_lossy().to_string();
+            let child_url = url.clone() + &url_encoding::encode_name(child.file_name()) + if file_type.is_dir() { "/" } else { "" };
+            let child_relative_path = if relative_path.is_empty() { name } else { relative_path.clone() + "/" + &name };
+            stack.push((child.path(), child_url, child_relative_path));
+        }
+    }
+    files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    files
+}
@@ -0,0 +1,30 @@
+use std::{ffi::{OsStr, OsString}, os::unix::ffi::{OsStrExt, OsStringExt}};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Everything but RFC 3986's unreserved characters, so a name can't be mistaken for anything else in a url, like `:` for a scheme
+/// in a relative link or `+` for a space
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Encodes a file name as one segment of a url path
+///
+/// It's encoded from the name's bytes rather than its text, so names that aren't valid UTF-8 get each of their bytes escaped,
+/// and decode_name gives back exactly the same name
+pub fn encode_name<S: AsRef<OsStr>>(name: S) -> String {
+    percent_encode(name.as_ref().as_bytes(), SEGMENT).to_string()
+}
+
+/// Decodes one segment of a url path into the file name it stands for
+pub fn decode_name<S: AsRef<str>>(segment: S) -> OsString {
+    OsString::from_vec(percent_decode_str(segment.as_ref()).collect())
+}
+
+/// Decodes a url path for showing, with bytes that aren't UTF-8 replaced
+pub fn decode_lossy<S: AsRef<str>>(path: S) -> String {
+    percent_decode_str(path.as_ref()).decode_utf8_lossy().to_string()
+}
+
+/// Re-encodes a request path the way urls to it are made, since clients are free to escape more or less than that
+/// (`%7e`, `%7E` and `~` are all the same path)
+pub fn normalize_path<S: AsRef<str>>(path: S) -> String {
+    path.as_ref().split('/').map(|i| encode_name(decode_name(i))).collect::<Vec<_>>().join("/")
+}
@@ -0,0 +1,76 @@
+use std::{cmp::Reverse, path::Path};
+use serde::{Deserialize, Serialize};
+use crate::{nested_listing::{self, NestedEntry, WalkedEntry}, AppState};
+
+pub const DEFAULT_DEPTH: usize = 2;
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// An entry in the disk usage breakdown returned by `?usage=true`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UsageEntry {
+    name: String,
+    url: String,
+    directory: bool,
+    size: Option<u64>,
+    size_computing: bool,
+    /// The largest entries inside the directory, largest first
+    ///
+    /// Empty for files and for directories past the requested depth, check `expanded` to tell those apart from empty directories
+    children: Vec<UsageEntry>,
+    expanded: bool,
+    /// How many entries didn't make the limit, and their combined size
+    other_count: usize,
+    other_size: u64,
+}
+
+impl NestedEntry for UsageEntry {
+    /// How many of the largest entries are kept in each directory
+    type Options = usize;
+
+    fn new(entry: &WalkedEntry) -> Self {
+        UsageEntry {
+            name: entry.name.clone(),
+            url: entry.url.clone(),
+            directory: entry.directory,
+            size: entry.size,
+            size_computing: entry.size_computing,
+            children: Vec::new(),
+            expanded: false,
+            other_count: 0,
+            other_size: 0,
+        }
+    }
+
+    fn choose_children(&mut self, mut entries: Vec<WalkedEntry>, limit: &usize) -> Vec<WalkedEntry> {
+        // directories that are still being computed go last, since None is smaller than any Some
+        entries.sort_unstable_by_key(|i| Reverse(i.size));
+        let others = entries.split_off((*limit).min(entries.len()));
+        self.other_count = others.len();
+        self.other_size = others.iter().filter_map(|i| i.size).sum();
+        entries
+    }
+
+    fn add_children(&mut self, children: Vec<Self>) {
+        self.children = children;
+        self.expanded = true;
+    }
+
+    fn children_size(&self) -> Option<u64> {
+        let computing = self.children.iter().any(|i| i.size_computing);
+        (!computing).then(|| self.children.iter().filter_map(|i| i.size).sum::<u64>() + self.other_size)
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.size = Some(size);
+    }
+}
+
+/// Returns the breakdown of `path`, where `url` is the (already encoded) url of the directory
+pub fn directory_usage(state: &AppState, path: &Path, name: String, url: String, depth: usize, limit: usize) -> UsageEntry {
+    nested_listing::directory(state, path, name, url, depth, &limit)
+}
+
+/// Returns the breakdown of the virtual directory, which contains every served path
+pub fn virtual_directory_usage(state: &AppState, name: String, depth: usize, limit: usize) -> UsageEntry {
+    nested_listing::virtual_directory(state, name, depth, &limit)
+}
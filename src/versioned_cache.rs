@@ -0,0 +1,35 @@
+use std::{collections::HashMap, fs, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+/// The mtime and size of a file, which what's worked out from it stays valid for
+pub type FileVersion = (i64, i64, u64);
+
+pub fn file_version(metadata: &fs::Metadata) -> FileVersion {
+    (metadata.mtime(), metadata.mtime_nsec(), metadata.size())
+}
+
+/// What's been worked out from files, kept until they change, for at most `capacity` of them at once
+#[derive(Debug, Clone)]
+pub struct VersionedCache<V> {
+    capacity: usize,
+    entries: Arc<Mutex<HashMap<PathBuf, (FileVersion, V)>>>,
+}
+
+impl<V: Clone> VersionedCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Arc::default() }
+    }
+
+    /// Returns what's cached for `path`, if it's from the same `version` of the file
+    pub fn get(&self, path: &Path, version: FileVersion) -> Option<V> {
+        self.entries.lock().unwrap().get(path).filter(|(cached_version, _)| *cached_version == version).map(|(_, value)| value.clone())
+    }
+
+    pub fn insert(&self, path: &Path, version: FileVersion, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        // starting over is much simpler than keeping track of which ones were used the longest ago, and it's rare
+        if entries.len() >= self.capacity && !entries.contains_key(path) {
+            entries.clear();
+        }
+        entries.insert(path.to_path_buf(), (version, value));
+    }
+}
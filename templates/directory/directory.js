@@ -1,8 +1,9 @@
 /**
- * @typedef { { name: string, url: string, directory: boolean, size?: number, modified: number } } EntryData
+ * @typedef { { name: string, url: string, directory: boolean, size?: number, size_computing: boolean, modified: number, thumbnail?: string, mime_type?: string, width?: number, height?: number, archive: boolean } } EntryData
  */
-/** @type { { title: string, path_components: { name: string, url: string }[], entries: EntryData[], upload_enabled: boolean, upload_overwrite: boolean, virtual_directory: boolean, default_view: string } } */
-let data = JSON.parse(`{{ data|json|safe }}`)
+/** @type { { title: string, path_components: { name: string, url: string }[], entries: EntryData[], upload_enabled: boolean, upload_overwrite: boolean, virtual_directory: boolean, default_view: string, readme?: string, content_search: boolean, total: number } } */
+// not put in a string and parsed, since backslashes and backticks in names (or the README) would break that
+let data = {{ data|json|safe }}
 
 /* ------------------------- createElement function ------------------------- */
 // {% raw %}
@@ -108,12 +109,16 @@ const icons = {
 	draft: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M255.38-120q-23.05 0-39.22-16.16Q200-152.33 200-175.38v-609.24q0-23.05 16.16-39.22Q232.33-840 255.38-840h334.08L760-669.46v494.08q0 23.05-16.16 39.22Q727.67-120 704.62-120H255.38Zm318.7-535.54v-153.69h-318.7q-9.23 0-16.92 7.69-7.69 7.69-7.69 16.92v609.24q0 9.23 7.69 16.92 7.69 7.69 16.92 7.69h449.24q9.23 0 16.92-7.69 7.69-7.69 7.69-16.92v-480.16H574.08ZM230.77-809.23v153.69-153.69 658.46-658.46Z"/></svg>'),
 	gridView: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M140-520v-300h300v300H140Zm0 380v-300h300v300H140Zm380-380v-300h300v300H520Zm0 380v-300h300v300H520ZM200-580h180v-180H200v180Zm380 0h180v-180H580v180Zm0 380h180v-180H580v180Zm-380 0h180v-180H200v180Zm380-380Zm0 200Zm-200 0Zm0-200Z"/></svg>'),
 	viewList: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M350-240h437.69q4.62 0 8.46-3.85 3.85-3.84 3.85-8.46V-357H350v117ZM160-603h130v-117H172.31q-4.62 0-8.46 3.85-3.85 3.84-3.85 8.46V-603Zm0 187h130v-127H160v127Zm12.31 176H290v-117H160v104.69q0 4.62 3.85 8.46 3.84 3.85 8.46 3.85ZM350-416h450v-127H350v127Zm0-187h450v-104.69q0-4.62-3.85-8.46-3.84-3.85-8.46-3.85H350v117ZM172.31-180Q142-180 121-201q-21-21-21-51.31v-455.38Q100-738 121-759q21-21 51.31-21h615.38Q818-780 839-759q21 21 21 51.31v455.38Q860-222 839-201q-21 21-51.31 21H172.31Z"/></svg>'),
+	photoLibrary: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M360-400h400L622-580l-92 120-62-80-108 140Zm-40 160q-33 0-56.5-23.5T240-320v-480q0-33 23.5-56.5T320-880h480q33 0 56.5 23.5T880-800v480q0 33-23.5 56.5T800-240H320Zm0-80h480v-480H320v480ZM160-80q-33 0-56.5-23.5T80-160v-560h80v560h560v80H160Zm160-720v480-480Z"/></svg>'),
 	tableRowsNarrow: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M760-355v-95H200v95h560Zm0-155v-95H200v95h560Zm0-155v-82.69q0-5.39-3.46-8.85t-8.85-3.46H212.31q-5.39 0-8.85 3.46t-3.46 8.85V-665h560ZM212.31-140Q182-140 161-161q-21-21-21-51.31v-535.38Q140-778 161-799q21-21 51.31-21h535.38Q778-820 799-799q21 21 21 51.31v535.38Q820-182 799-161q-21 21-51.31 21H212.31ZM760-212.31V-295H200v82.69q0 5.39 3.46 8.85t8.85 3.46h535.38q5.39 0 8.85-3.46t3.46-8.85Z"/></svg>'),
 	arrowUpwardAlt: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M450-253.85v-381.84l-154 154-42.15-42.16L480-750l226.15 226.15L664-481.69l-154-154v381.84h-60Z"/></svg>'),
 	arrowDownwardAlt: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M480-253.85 253.85-480 296-522.15l154 154V-750h60v381.85l154-154L706.15-480 480-253.85Z"/></svg>'),
 	sortByAlpha: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="m92.31-288.46 149.23-383.08h63.69l148.46 383.08h-63.54l-34.76-95.23H190.61l-35.07 95.23H92.31Zm116.92-147.08h125.54l-59.54-166.92H270l-60.77 166.92Zm347.23 147.08v-56.77l205.08-272H564.46v-54.31h265.08v56.77l-203.85 272h205.85v54.31H556.46ZM369.23-763.85 480-874.61l110.77 110.76H369.23ZM480-85.39 369.23-196.15h221.54L480-85.39Z"/></svg>'),
 	schedule: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="m618.92-298.92 42.16-42.16L510-492.16V-680h-60v212.15l168.92 168.93ZM480.07-100q-78.84 0-148.21-29.92t-120.68-81.21q-51.31-51.29-81.25-120.63Q100-401.1 100-479.93q0-78.84 29.92-148.21t81.21-120.68q51.29-51.31 120.63-81.25Q401.1-860 479.93-860q78.84 0 148.21 29.92t120.68 81.21q51.31 51.29 81.25 120.63Q860-558.9 860-480.07q0 78.84-29.92 148.21t-81.21 120.68q-51.29 51.31-120.63 81.25Q558.9-100 480.07-100ZM480-480Zm0 320q133 0 226.5-93.5T800-480q0-133-93.5-226.5T480-800q-133 0-226.5 93.5T160-480q0 133 93.5 226.5T480-160Z"/></svg>'),
 	storage: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M134.62-168.46v-143.08h690.76v143.08H134.62Zm70.77-35.39h72.3v-72.3h-72.3v72.3Zm-70.77-444.61v-143.08h690.76v143.08H134.62Zm70.77-35.39h72.3v-72.3h-72.3v72.3Zm-70.77 275.39v-143.08h690.76v143.08H134.62Zm70.77-35.39h72.3v-72.3h-72.3v72.3Z"/></svg>'),
+	barChart: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M160-200v-60h640v60H160Zm0-170v-60h420v60H160Zm0-170v-60h560v60H160Zm0-170v-60h260v60H160Z"/></svg>'),
+	manageSearch: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M80-200v-80h400v80H80Zm0-200v-80h200v80H80Zm0-200v-80h200v80H80Zm744 400L670-354q-24 17-52.5 25.5T560-320q-83 0-141.5-58.5T360-520q0-83 58.5-141.5T560-720q83 0 141.5 58.5T760-520q0 29-8.5 57.5T726-410l154 154-56 56ZM560-400q50 0 85-35t35-85q0-50-35-85t-85-35q-50 0-85 35t-35 85q0 50 35 85t85 35Z"/></svg>'),
+	close: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M256-213.85 213.85-256l224-224-224-224L256-746.15l224 224 224-224L746.15-704l-224 224 224 224L704-213.85l-224-224-224 224Z"/></svg>'),
 	chevron_right: () => createElement('<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 -960 960 960" fill="currentColor"><path d="M504-480 320-664l56-56 240 240-240 240-56-56 184-184Z"/></svg>'),
 }
 
@@ -121,13 +126,14 @@ const topBarContainer = document.getElementById('top-bar')
 const pathWrapper = document.getElementById('path-wrapper')
 const main = document.querySelector('main')
 
+// files open a preview page instead of downloading straight away, the preview has a download button
 const pointerDownJSAnchor = anchorElement => {
 	anchorElement.addEventListener('pointerdown', e => {
 		if (e.buttons != 1 || e.pointerType != 'mouse') return
 		if (anchorElement.href.endsWith('/')) {
 			goToPath(anchorElement.href)
 		} else {
-			createElement('a', { href: anchorElement.href, download: '' }).click()
+			location.href = anchorElement.href + '?view=1'
 		}
 	})
 	anchorElement.addEventListener('click', e => {
@@ -135,7 +141,7 @@ const pointerDownJSAnchor = anchorElement => {
 		if (anchorElement.href.endsWith('/')) {
 			goToPath(anchorElement.href)
 		} else {
-			createElement('a', { href: anchorElement.href, download: '' }).click()
+			location.href = anchorElement.href + '?view=1'
 		}
 	})
 }
@@ -209,12 +215,8 @@ async function uploadFiles(files = [], url = '') {
 		progressDisplayContainer.remove()
 	})
 	xhr.addEventListener('load', () => {
-		if (url == '') {
-			const newFilenames = xhr.response
-			data.entries.splice(0, data.entries.length, ...data.entries.filter(i => !newFilenames.includes(i.name)))
-			data.entries.push(...[...files].map(file => ({ name: file.name, url: file.name, directory: false, size: file.size })).map((i, index) => { i.name = newFilenames[index]; return i }))
-			changeDataSortingAndUpdate()
-		}
+		// the new files could belong anywhere in the listing, possibly past the pages that are loaded, so it's fetched again
+		if (url == '') changeDataSortingAndUpdate()
 	})
 	xhr.open('POST', url, true)
 	xhr.send(formData)
@@ -334,6 +336,9 @@ function getHumanReadableFileSize(bytes) {
 	return `${bytes} B`
 }
 
+// archives are browsed like directories, which is done by adding a slash
+const getFileHref = (url, archive) => archive ? url + '/' : url
+
 let currentView = data.default_view
 const minWidthForFourColumns = 738
 if (currentView == 'grid' && innerWidth < minWidthForFourColumns) currentView = 'list'
@@ -341,39 +346,50 @@ if (currentView == 'grid' && innerWidth < minWidthForFourColumns) currentView =
 
 function setGridView() {
 	entryContainer.replaceChildren()
-	entryContainer.classList.remove('list-view', 'compact-list-view')
+	entryContainer.classList.remove('list-view', 'compact-list-view', 'gallery-view')
 	entryContainer.classList.add('grid-view')
 	currentView = 'grid'
+	appendGridEntries(data.entries)
+}
 
-	data.entries.filter(i => i.directory).map(({ name, url, directory, size }) => {
+/** @param { EntryData[] } entries */
+function appendGridEntries(entries) {
+	entries.filter(i => i.directory).map(({ name, url, directory, size }) => {
 		const li = createElement('li', { parent: entryContainer })
 		const a = createElement('a', { href: url, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] }, icons.folder(), createElement({ text: name }))
 	})
-	data.entries.filter(i => !i.directory).map(({ name, url, directory, size }) => {
+	entries.filter(i => !i.directory).map(({ name, url, directory, size, thumbnail, archive }) => {
 		const li = createElement('li', { parent: entryContainer })
-		const a = createElement('a', { download: '', href: url, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] }, icons.draft(), createElement({ text: name }))
+		// images that fail to load (or that the server can't decode after all) go back to the generic icon
+		const icon = thumbnail != null ? createElement('img', { src: thumbnail, loading: 'lazy', alt: '', class: 'thumbnail', onerror: e => e.target.replaceWith(icons.draft()) }) : icons.draft()
+		const a = createElement('a', { download: archive ? null : '', href: getFileHref(url, archive), parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] }, icon, createElement({ text: name }))
 	})
 }
 
 function setListView() {
 	entryContainer.replaceChildren()
-	entryContainer.classList.remove('grid-view', 'compact-list-view')
+	entryContainer.classList.remove('grid-view', 'compact-list-view', 'gallery-view')
 	entryContainer.classList.add('list-view')
 	currentView = 'list'
+	appendListEntries(data.entries)
+}
 
-	data.entries.filter(i => i.directory).map(({ name, url, directory, size, modified }) => {
+/** @param { EntryData[] } entries */
+function appendListEntries(entries) {
+	entries.filter(i => i.directory).map(({ name, url, directory, size, size_computing, modified }) => {
 		const li = createElement('li', { parent: entryContainer })
 		const a = createElement('a', { href: url, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] },
 			icons.folder(),
 			createElement(
 				createElement({ text: name, class: 'name' }),
 				...((size != null) ? [createElement({ text: size ? getHumanReadableFileSize(size) : 'empty', class: 'size' })] : []),
+				...((size == null && size_computing) ? [createElement({ text: 'calculating...', class: 'size' })] : []),
 			)
 		)
 	})
-	data.entries.filter(i => !i.directory).map(({ name, url, directory, size, modified }) => {
+	entries.filter(i => !i.directory).map(({ name, url, directory, size, modified, archive }) => {
 		const li = createElement('li', { parent: entryContainer })
-		const a = createElement('a', { download: '', href: url, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] },
+		const a = createElement('a', { download: archive ? null : '', href: getFileHref(url, archive), parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] },
 			icons.draft(),
 			createElement(
 				createElement({ text: name, class: 'name' }),
@@ -385,20 +401,94 @@ function setListView() {
 
 function setCompactListView() {
 	entryContainer.replaceChildren()
-	entryContainer.classList.remove('grid-view', 'list-view')
+	entryContainer.classList.remove('grid-view', 'list-view', 'gallery-view')
 	entryContainer.classList.add('compact-list-view')
 	currentView = 'compact-list'
+	appendCompactListEntries(data.entries)
+}
 
-	data.entries.filter(i => i.directory).map(({ name, url, directory, size }) => {
+/** @param { EntryData[] } entries */
+function appendCompactListEntries(entries) {
+	entries.filter(i => i.directory).map(({ name, url, directory, size }) => {
 		const li = createElement('li', { parent: entryContainer })
 		const a = createElement('a', { href: url, text: name + '/', parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] })
 	})
-	data.entries.filter(i => !i.directory).map(({ name, url, directory, size }) => {
+	entries.filter(i => !i.directory).map(({ name, url, directory, size, archive }) => {
 		const li = createElement('li', { parent: entryContainer })
-		const a = createElement('a', { download: '', href: url, text: name, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] })
+		const a = createElement('a', { download: archive ? null : '', href: getFileHref(url, archive), text: name, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] })
 	})
 }
 
+/** @param { EntryData } entry */
+const isMedia = entry => !entry.directory && (entry.mime_type?.startsWith('image/') || entry.mime_type?.startsWith('video/'))
+
+function setGalleryView() {
+	entryContainer.replaceChildren()
+	entryContainer.classList.remove('grid-view', 'list-view', 'compact-list-view')
+	entryContainer.classList.add('gallery-view')
+	currentView = 'gallery'
+
+	// everything that isn't an image or a video is listed compactly above the tiles
+	data.entries.filter(i => i.directory).map(({ name, url }) => {
+		const li = createElement('li', { class: 'other', parent: entryContainer })
+		const a = createElement('a', { href: url, parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] }, icons.folder(), createElement({ text: name }))
+	})
+	data.entries.filter(i => !i.directory && !isMedia(i)).map(({ name, url, archive }) => {
+		const li = createElement('li', { class: 'other', parent: entryContainer })
+		const a = createElement('a', { download: archive ? null : '', href: getFileHref(url, archive), parent: li, js: [pointerDownJSAnchor, makeAnchorDragAndDroppableIfNecessary] }, icons.draft(), createElement({ text: name }))
+	})
+	const mediaEntries = data.entries.filter(isMedia)
+	mediaEntries.map((entry, index) => {
+		const { name, url, thumbnail, mime_type, width, height } = entry
+		// the server gives image dimensions so the tiles don't jump around as they load, videos get a 16:9 guess
+		const aspectRatio = (width && height) ? width / height : 16 / 9
+		const li = createElement('li', { class: 'tile', title: name, parent: entryContainer, style: { aspectRatio: String(aspectRatio), flexGrow: String(aspectRatio) } })
+		const media = mime_type.startsWith('video/')
+			? createElement('video', { src: url, preload: 'metadata', muted: true })
+			: createElement('img', { src: thumbnail ?? url, loading: 'lazy', alt: name })
+		const a = createElement('a', { href: url, parent: li, onclick: e => { e.preventDefault(); openLightbox(mediaEntries, index) } }, media)
+	})
+}
+
+/* -------------------------------- Lightbox -------------------------------- */
+const lightbox = createElement('dialog', { id: 'lightbox', parent: document.body })
+const lightboxMediaContainer = createElement({ class: 'media', parent: lightbox })
+const lightboxCaption = createElement({ class: 'caption', parent: lightbox })
+let lightboxEntries = []
+let lightboxIndex = 0
+
+function showLightboxEntry(index) {
+	lightboxIndex = (index + lightboxEntries.length) % lightboxEntries.length
+	const { name, url, mime_type, width, height } = lightboxEntries[lightboxIndex]
+	lightboxMediaContainer.replaceChildren(mime_type.startsWith('video/')
+		? createElement('video', { src: url, controls: true, autoplay: true })
+		: createElement('img', { src: url, alt: name, width, height }))
+	lightboxCaption.replaceChildren(
+		createElement('a', { href: url, download: '', text: name }),
+		createElement('span', { text: `${lightboxIndex + 1} / ${lightboxEntries.length}` }),
+	)
+}
+
+function openLightbox(entries, index) {
+	lightboxEntries = entries
+	showLightboxEntry(index)
+	lightbox.showModal()
+}
+
+createElement('button', { class: 'previous', title: 'Previous', onclick: () => showLightboxEntry(lightboxIndex - 1), parent: lightbox }, icons.chevron_right())
+createElement('button', { class: 'next', title: 'Next', onclick: () => showLightboxEntry(lightboxIndex + 1), parent: lightbox }, icons.chevron_right())
+createElement('button', { class: 'close', title: 'Close', onclick: () => lightbox.close(), parent: lightbox }, icons.close())
+
+lightbox.addEventListener('keydown', e => {
+	if (e.key == 'ArrowLeft') showLightboxEntry(lightboxIndex - 1)
+	if (e.key == 'ArrowRight') showLightboxEntry(lightboxIndex + 1)
+})
+// stops videos from playing in the background
+lightbox.addEventListener('close', () => lightboxMediaContainer.replaceChildren())
+lightbox.addEventListener('click', e => {
+	if (e.target == lightbox || e.target == lightboxMediaContainer) lightbox.close()
+})
+
 const topBarElement = document.getElementById('top-bar')
 const topRightControls = createElement({ id: 'top-right-controls', parent: topBarElement })
 
@@ -416,22 +506,36 @@ const viewChangeClick = f => {
 const viewChangeGridViewButton = createElement('button', { class: 'grid-view', onpointerdown: viewChangePointerDown(setGridView), onclick: viewChangeClick(setGridView), parent: viewChangeContainer }, icons.gridView())
 const viewChangeListViewButton = createElement('button', { class: 'list-view', onpointerdown: viewChangePointerDown(setListView), onclick: viewChangeClick(setListView), parent: viewChangeContainer }, icons.viewList())
 const viewChangeCompactListViewButton = createElement('button', { class: 'compact-list-view', onpointerdown: viewChangePointerDown(setCompactListView), onclick: viewChangeClick(setCompactListView), parent: viewChangeContainer }, icons.tableRowsNarrow())
+const viewChangeGalleryViewButton = createElement('button', { class: 'gallery-view', onpointerdown: viewChangePointerDown(setGalleryView), onclick: viewChangeClick(setGalleryView), parent: viewChangeContainer }, icons.photoLibrary())
 
 function setView() {
 	(({
 		'grid': setGridView,
 		'list': setListView,
 		'compact-list': setCompactListView,
+		'gallery': setGalleryView,
 	})[currentView])()
 }
 
+/** @param { EntryData[] } entries */
+function appendEntries(entries) {
+	(({
+		'grid': appendGridEntries,
+		'list': appendListEntries,
+		'compact-list': appendCompactListEntries,
+		// media tiles go after everything else, so the whole gallery is laid out again
+		'gallery': setGalleryView,
+	})[currentView])(entries)
+}
+
 /* -------------------------------------------------------------------------- */
 /*                                   Sorting                                  */
 /* -------------------------------------------------------------------------- */
 let currentSorting = 'name'
 let currentSortingReversed = false
 
-function changeDataSortingAndUpdate() {
+/** Sorts search results, which are put together here instead of coming sorted from the server */
+function sortEntries() {
 	switch (currentSorting) {
 		case 'name':
 			data.entries.sort((a, b) => (a.name.toLowerCase() + flipCase(a.name)).charCodeAt() - (b.name.toLowerCase() + flipCase(b.name)).charCodeAt())
@@ -447,7 +551,15 @@ function changeDataSortingAndUpdate() {
 			throw new Error('Unknown sorting key')
 	}
 	if (currentSortingReversed) data.entries.reverse()
+}
 
+function changeDataSortingAndUpdate() {
+	// directories can be too big to have every entry of, so the server sorts them and the first page is fetched again
+	if (directoryEntries == null) {
+		loadFirstPage()
+		return
+	}
+	sortEntries()
 	setView()
 }
 
@@ -498,7 +610,277 @@ sortReverseButton.addEventListener('click', e => {
 	sortReverseButtonOnClick(e)
 })
 
-changeDataSortingAndUpdate()
+/* -------------------------------------------------------------------------- */
+/*                                   Paging                                   */
+/* -------------------------------------------------------------------------- */
+// same as listing::PAGE_SIZE, which is how many entries the page starts with
+const pageSize = 500
+let pageAbortController = null
+
+/** Returns the query for a page of the directory, sorted however it's currently set to be */
+function getListingQuery(offset) {
+	// names are sorted A to Z by default, and times and sizes newest and largest first
+	const descending = (currentSorting != 'name') != currentSortingReversed
+	return `?data=true&sort=${currentSorting}&order=${descending ? 'desc' : 'asc'}&offset=${offset}&limit=${pageSize}`
+}
+
+/** Fetches a page, returns null if another one was asked for before it arrived */
+async function fetchPage(offset) {
+	pageAbortController?.abort()
+	const abortController = pageAbortController = new AbortController()
+	try {
+		return await (await fetch(location.pathname + getListingQuery(offset), { signal: abortController.signal })).json()
+	} catch (e) {
+		if (e.name == 'AbortError') return null
+		throw e
+	} finally {
+		if (pageAbortController == abortController) pageAbortController = null
+	}
+}
+
+async function loadFirstPage() {
+	const page = await fetchPage(0)
+	if (page == null) return
+	data.entries = page.entries
+	data.total = page.total
+	setView()
+}
+
+let pageEndVisible = false
+
+async function loadNextPage() {
+	// search results aren't paged, and there's no need to fetch the same page twice
+	if (directoryEntries != null || data.entries.length >= data.total || pageAbortController) return
+	const page = await fetchPage(data.entries.length)
+	if (page == null) return
+	data.entries.push(...page.entries)
+	data.total = page.total
+	appendEntries(page.entries)
+	// the observer only says when that changes, so a short page on a tall screen needs the next one straight away
+	if (pageEndVisible) loadNextPage()
+}
+
+const pageEnd = createElement({ id: 'page-end', insertBefore: document.getElementById('readme') })
+// the next page is fetched a bit before the end is reached, so scrolling doesn't stop
+new IntersectionObserver(([entry]) => {
+	pageEndVisible = entry.isIntersecting
+	if (pageEndVisible) loadNextPage()
+}, { rootMargin: '0px 0px 1000px 0px' }).observe(pageEnd)
+
+/* -------------------------------------------------------------------------- */
+/*                                 Disk Usage                                 */
+/* -------------------------------------------------------------------------- */
+/**
+ * @typedef { { name: string, url: string, directory: boolean, size?: number, size_computing: boolean, children: UsageEntry[], expanded: boolean, other_count: number, other_size: number } } UsageEntry
+ */
+
+const usageDialog = createElement('dialog', { id: 'usage', parent: document.body })
+// clicking the backdrop counts as clicking the dialog itself
+usageDialog.addEventListener('click', e => {
+	if (e.target == usageDialog) usageDialog.close()
+})
+
+/** @param { UsageEntry } entry */
+function getUsageSizeText(entry) {
+	if (entry.size != null) return getHumanReadableFileSize(entry.size)
+	return entry.size_computing ? 'calculating...' : 'unknown'
+}
+
+/**
+ * Returns a row with a bar showing how much of the parent the entry takes up, directories can be opened to show what's inside them
+ * @param { UsageEntry } entry
+ * @param { number | undefined } parentSize
+ */
+function createUsageEntryElement(entry, parentSize) {
+	const rowContent = [
+		createElement({ class: 'bar' }, createElement({ class: 'bar-completed', style: { width: `${parentSize ? (entry.size ?? 0) / parentSize * 100 : 0}%` } })),
+		createElement({ class: 'size', text: getUsageSizeText(entry) }),
+		createElement({ class: 'name', text: entry.directory ? entry.name + '/' : entry.name }),
+	]
+	if (!entry.directory) return createElement({ class: 'usage-entry' }, createElement({ class: 'usage-row' }, ...rowContent))
+
+	const details = createElement('details', { class: 'usage-entry' }, createElement('summary', { class: 'usage-row' }, ...rowContent))
+	const childrenContainer = createElement({ class: 'usage-children', parent: details })
+	/** @param { UsageEntry } entry */
+	const addChildren = entry => {
+		if (!entry.children.length && !entry.other_count) childrenContainer.append(createElement({ class: 'usage-empty', text: 'empty' }))
+		childrenContainer.append(...entry.children.map(i => createUsageEntryElement(i, entry.size)))
+		if (entry.other_count) {
+			childrenContainer.append(createElement({ class: 'usage-entry' }, createElement({ class: 'usage-row usage-other' },
+				createElement({ class: 'bar' }, createElement({ class: 'bar-completed', style: { width: `${entry.size ? entry.other_size / entry.size * 100 : 0}%` } })),
+				createElement({ class: 'size', text: getHumanReadableFileSize(entry.other_size) }),
+				createElement({ class: 'name', text: `${entry.other_count} more` }),
+			)))
+		}
+	}
+	if (entry.expanded) addChildren(entry)
+	// directories past the requested depth are fetched when they're opened
+	details.addEventListener('toggle', async () => {
+		if (!details.open || entry.expanded) return
+		entry.expanded = true
+		addChildren(await (await fetch(entry.url + '?usage=true&depth=1')).json())
+	})
+	return details
+}
+
+async function showUsage() {
+	/** @type { UsageEntry } */
+	const usage = await (await fetch(location.pathname + '?usage=true')).json()
+	usageDialog.replaceChildren(
+		createElement({ id: 'usage-header' },
+			createElement({ text: `${usage.name} — ${getUsageSizeText(usage)}` }),
+			createElement('button', { onclick: () => usageDialog.close() }, icons.close()),
+		),
+	)
+	const root = createUsageEntryElement(usage, usage.size)
+	root.open = true
+	usageDialog.append(...root.querySelector('.usage-children').childNodes)
+	usageDialog.showModal()
+}
+
+const usageButton = createElement('button', { id: 'usage-button', title: 'Disk usage', onclick: showUsage, insertBefore: sortOptionsContainer }, icons.barChart())
+
+/* -------------------------------------------------------------------------- */
+/*                                   Search                                   */
+/* -------------------------------------------------------------------------- */
+/**
+ * @typedef { EntryData & { lines: { number: number, text: string }[], line_count: number } } FileMatch
+ */
+
+// the directory's own entries, put back when the search is cleared
+let directoryEntries = null
+let searchAbortController = null
+let searchTimeout = null
+let contentSearch = false
+
+const grepResultsElement = createElement('ul', { id: 'grep-results', hidden: true, insertBefore: document.getElementById('readme') })
+
+function clearSearch() {
+	searchAbortController?.abort()
+	clearTimeout(searchTimeout)
+	searchInput.value = ''
+	if (directoryEntries) data.entries = directoryEntries
+	directoryEntries = null
+	grepResultsElement.hidden = true
+	grepResultsElement.replaceChildren()
+	entryContainer.hidden = false
+}
+
+// results are shown with their path so ones with the same name can be told apart
+// names that aren't UTF-8 can't be decoded, so they're left escaped
+const decodeSegment = segment => { try { return decodeURIComponent(segment) } catch { return segment } }
+const getSearchResultPath = url => url.slice(location.pathname.length).replace(/\/$/, '').split('/').map(decodeSegment).join('/')
+
+async function search(query) {
+	if (!query) {
+		clearSearch()
+		changeDataSortingAndUpdate()
+		return
+	}
+	searchAbortController?.abort()
+	searchAbortController = new AbortController()
+	// a page arriving now would end up in the results
+	pageAbortController?.abort()
+	try {
+		contentSearch ? await searchContents(query, searchAbortController.signal) : await searchNames(query, searchAbortController.signal)
+	} catch (e) {
+		if (e.name != 'AbortError') throw e
+	}
+}
+
+async function searchNames(query, signal) {
+	grepResultsElement.hidden = true
+	entryContainer.hidden = false
+	directoryEntries ??= data.entries
+	data.entries = []
+	changeDataSortingAndUpdate()
+	// anything that looks like a glob is searched as one
+	const mode = /[*?[]/.test(query) ? 'glob' : 'substring'
+	const response = await fetch(`${location.pathname}?search=${encodeURIComponent(query)}&mode=${mode}`, { signal })
+	if (!response.ok) return
+	// results are streamed one per line, so they're shown as they're found instead of after the whole tree is walked
+	const reader = response.body.pipeThrough(new TextDecoderStream()).getReader()
+	let buffered = ''
+	while (true) {
+		const { done, value } = await reader.read()
+		if (done) break
+		const lines = (buffered + value).split('\n')
+		buffered = lines.pop()
+		for (const line of lines) {
+			/** @type { EntryData } */
+			const entry = JSON.parse(line)
+			entry.name = getSearchResultPath(entry.url)
+			data.entries.push(entry)
+		}
+		changeDataSortingAndUpdate()
+	}
+}
+
+/** Returns the text with every occurrence of the query (ignoring case) in a mark element */
+function highlightQuery(text, query) {
+	const lowercaseText = text.toLowerCase()
+	const lowercaseQuery = query.toLowerCase()
+	const parts = []
+	let index = 0
+	while (true) {
+		const matchIndex = lowercaseText.indexOf(lowercaseQuery, index)
+		if (matchIndex == -1) break
+		parts.push(text.slice(index, matchIndex), createElement('mark', { text: text.slice(matchIndex, matchIndex + query.length) }))
+		index = matchIndex + query.length
+	}
+	parts.push(text.slice(index))
+	return parts
+}
+
+async function searchContents(query, signal) {
+	const response = await fetch(`${location.pathname}?grep=${encodeURIComponent(query)}`, { signal })
+	if (!response.ok) return
+	/** @type { { indexing: boolean, files: FileMatch[] } } */
+	const results = await response.json()
+	entryContainer.hidden = true
+	grepResultsElement.hidden = false
+	grepResultsElement.replaceChildren(
+		results.indexing ? createElement('li', { class: 'grep-notice', text: 'Still indexing, some files might be missing' }) : null,
+		results.files.length ? null : createElement('li', { class: 'grep-notice', text: 'No matches' }),
+		...results.files.map(file => createElement('li', {},
+			createElement('a', { href: file.url + '?view=1', text: getSearchResultPath(file.url) }),
+			createElement('ol', {}, ...file.lines.map(line => createElement('li', {},
+				createElement('span', { class: 'line-number', text: line.number }),
+				createElement('code', {}, ...highlightQuery(line.text, query)),
+			))),
+			file.line_count > file.lines.length ? createElement({ class: 'grep-more', text: `${file.line_count - file.lines.length} more` }) : null,
+		)),
+	)
+}
+
+const searchInput = createElement('input', {
+	id: 'search',
+	type: 'search',
+	placeholder: 'Search',
+	title: 'Search names in this directory and everything inside it, * and ? search with a glob',
+	oninput: () => {
+		clearTimeout(searchTimeout)
+		searchTimeout = setTimeout(() => search(searchInput.value), 300)
+	},
+	insertBefore: usageButton,
+})
+
+const contentSearchButton = data.content_search ? createElement('button', {
+	id: 'content-search-button',
+	title: 'Search file contents instead of names',
+	onclick: () => {
+		contentSearch = !contentSearch
+		contentSearchButton.classList.toggle('active', contentSearch)
+		searchInput.placeholder = contentSearch ? 'Search contents' : 'Search'
+		// the previous results were of the other kind
+		if (directoryEntries) data.entries = directoryEntries
+		directoryEntries = null
+		search(searchInput.value)
+	},
+	insertBefore: usageButton,
+}, icons.manageSearch()) : null
+
+setView()
 
 // refresh when loaded from bfcache
 addEventListener('pageshow', e => {
@@ -510,7 +892,9 @@ addEventListener('pageshow', e => {
 async function goToPath(path, pushState = true, updateData = true) {
 	if (pushState) history.pushState({}, '', path)
 	if (updateData) {
-		data = await (await fetch(path + '?data=true')).json()
+		clearSearch()
+		pageAbortController?.abort()
+		data = await (await fetch(path + getListingQuery(0))).json()
 	}
 	document.title = data.title
 	
@@ -524,7 +908,13 @@ async function goToPath(path, pushState = true, updateData = true) {
 		}
 	}
 
-	changeDataSortingAndUpdate()
+	/* --------------------------------- README --------------------------------- */
+	// the server sanitizes the README, so it's safe to put in as HTML
+	const readmeElement = document.getElementById('readme')
+	readmeElement.innerHTML = data.readme ?? ''
+	readmeElement.hidden = data.readme == null
+
+	setView()
 }
 
 goToPath(location.pathname, false, false)
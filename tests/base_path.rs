@@ -0,0 +1,41 @@
+//! Runs the client commands against a server with a base path, which the api is under too
+
+mod common;
+
+use std::{fs, process::Command};
+use common::{client, Server};
+
+const ARGS: &[&str] = &["--base-path", "/files", "--upload-overwrite"];
+const FILES: &[(&str, &str)] = &[("dir/a.txt", "a")];
+
+#[test]
+fn the_client_finds_the_base_path() {
+    let server = Server::shared("client", ARGS, FILES);
+    let url = server.url("/files/dir/");
+    let local = server.root.join("local");
+    fs::create_dir_all(&local).unwrap();
+
+    assert!(client(&local, &["ls", &url]).contains("a.txt"));
+    client(&local, &["get", &(url.clone() + "a.txt")]);
+    assert_eq!(fs::read_to_string(local.join("a.txt")).unwrap(), "a");
+    fs::write(local.join("b.txt"), "b").unwrap();
+    client(&local, &["put", &url, "b.txt"]);
+    assert_eq!(fs::read_to_string(server.root.join("shared").join("dir").join("b.txt")).unwrap(), "b");
+    client(&local, &["mirror", &url, "mirrored"]);
+    assert_eq!(fs::read_to_string(local.join("mirrored").join("b.txt")).unwrap(), "b");
+    fs::write(local.join("mirrored").join("c.txt"), "c").unwrap();
+    client(&local, &["sync", "mirrored", &url]);
+    assert_eq!(fs::read_to_string(server.root.join("shared").join("dir").join("c.txt")).unwrap(), "c");
+}
+
+#[test]
+fn the_base_path_can_be_given() {
+    let server = Server::shared("client-given", ARGS, FILES);
+    let local = server.root.join("local");
+    fs::create_dir_all(&local).unwrap();
+    assert!(client(&local, &["ls", "--base-path", "files", &server.url("/files/dir")]).contains("a.txt"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_gshare3")).args(["ls", &server.url("/dir/")]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("isn't under the server's base path, /files/"));
+}
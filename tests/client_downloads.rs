@@ -0,0 +1,40 @@
+//! Checks that downloads only pick up where they left off when the part that's there is of the same file
+
+mod common;
+
+use std::{fs, time::{Duration, UNIX_EPOCH}};
+use common::{client, Server};
+
+const CONTENTS: &[u8] = &[b'a'; 100_000];
+
+/// Leaves a part that's wrong after the first byte, with the modification time `modified` (in seconds)
+fn write_part(server: &Server, modified: u64) {
+    fs::create_dir_all(server.root.join("local")).unwrap();
+    let path = server.root.join("local").join("file.txt.part");
+    fs::write(&path, b"b").unwrap();
+    fs::File::options().write(true).open(&path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+}
+
+fn remote_modified(server: &Server) -> u64 {
+    server.get_json("/api/v1/stat/file.txt")["modified"].as_f64().unwrap() as u64
+}
+
+#[test]
+fn parts_of_the_same_file_are_continued() {
+    let server = Server::shared("resume", &["--private"], &[("file.txt", CONTENTS)]);
+    write_part(&server, remote_modified(&server));
+    client(&server.root.join("local"), &["get", &server.url("/file.txt")]);
+    // it's only the part that was there that can tell it was continued
+    assert_eq!(fs::read(server.root.join("local").join("file.txt")).unwrap(), [b"b", &CONTENTS[1..]].concat());
+}
+
+#[test]
+fn parts_of_another_version_are_started_over() {
+    let server = Server::shared("restart", &["--private"], &[("file.txt", CONTENTS)]);
+    // including one written after the file was last changed, like when it was replaced by an older one
+    for offset in [-10, 10] {
+        write_part(&server, remote_modified(&server).saturating_add_signed(offset));
+        client(&server.root.join("local"), &["get", &server.url("/file.txt")]);
+        assert_eq!(fs::read(server.root.join("local").join("file.txt")).unwrap(), CONTENTS, "{offset}");
+    }
+}
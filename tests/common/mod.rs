@@ -0,0 +1,144 @@
+//! Runs the server for the integration tests and talks plain HTTP to it, so the tests see exactly what a client would
+
+#![allow(dead_code)]
+
+use std::{fs, io::{Read, Write}, net::{IpAddr, Ipv4Addr, TcpListener, TcpStream}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process::{Child, Command, Stdio}, thread, time::{Duration, Instant}};
+use serde_json::Value;
+
+/// For servers started with Server::shared that only need the directory
+pub const NO_FILES: &[(&str, &str)] = &[];
+
+/// How long the server gets to start listening before the test gives up on it
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a response can take before the test gives up on it, rather than hanging if the server does
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Server {
+    process: Child,
+    pub port: u16,
+    /// A temporary directory the server runs in, so `args` can give paths relative to it
+    pub root: PathBuf,
+}
+
+impl Server {
+    /// Creates a temporary directory, lets `create_files` fill it, and serves it with `args` (after the ones that turn off
+    /// everything the tests don't need)
+    pub fn start(name: &str, args: &[&str], create_files: impl FnOnce(&Path)) -> Server {
+        let root = std::env::temp_dir().join(format!("gshare3-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        create_files(&root);
+
+        // asks the OS for a free port, which is very likely still free when the server starts a moment later
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let process = Command::new(env!("CARGO_BIN_EXE_gshare3"))
+            .current_dir(&root)
+            .args(["--no-port-increment", "--no-dir-sizes", "--no-thumbnails", "--no-content-search", "--no-mdns", "-p", &port.to_string()])
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut server = Server { process, port, root };
+        let start_time = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if let Some(status) = server.process.try_wait().unwrap() {
+                panic!("The server exited with {status}");
+            }
+            assert!(start_time.elapsed() < STARTUP_TIMEOUT, "The server didn't start listening within {STARTUP_TIMEOUT:?}");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// Serves a directory named "shared" containing `files` (paths relative to it, and their contents) with `args`. Paths ending
+    /// with a slash are empty directories, and the directories files are in are made as needed
+    pub fn shared<P: AsRef<Path>, C: AsRef<[u8]>>(name: &str, args: &[&str], files: &[(P, C)]) -> Server {
+        Server::start(name, &[&["shared"], args].concat(), |root| {
+            let shared = root.join("shared");
+            fs::create_dir_all(&shared).unwrap();
+            for (path, contents) in files {
+                let path = shared.join(path);
+                if path.as_os_str().as_bytes().ends_with(b"/") {
+                    fs::create_dir_all(&path).unwrap();
+                } else {
+                    fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    fs::write(&path, contents).unwrap();
+                }
+            }
+        })
+    }
+
+    /// The url of `path` on the server the way the client commands take it, without the scheme
+    pub fn url(&self, path: &str) -> String {
+        format!("127.0.0.1:{}{path}", self.port)
+    }
+
+    /// Makes a request and returns the status and body
+    pub fn request(&self, method: &str, path: &str, headers: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        self.request_to(IpAddr::V4(Ipv4Addr::LOCALHOST), method, path, headers, body)
+    }
+
+    /// Like request, but connecting to `ip` instead of 127.0.0.1, which is also the address the server sees the request come from
+    pub fn request_to(&self, ip: IpAddr, method: &str, path: &str, headers: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect((ip, self.port)).unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+        write!(stream, "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n{headers}\r\n", body.len()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap_or_else(|e| panic!("{method} {path} failed: {e}"));
+        let header_end = response.windows(4).position(|i| i == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..header_end]).to_lowercase();
+        let status = head[9..12].parse().unwrap();
+        let body = response[header_end + 4..].to_vec();
+        (status, if head.contains("transfer-encoding: chunked") { dechunk(&body) } else { body })
+    }
+
+    /// Uploads `files` (filename and contents) to `path` as one multipart form, the way the upload form does
+    pub fn upload(&self, path: &str, files: &[(&str, &str)]) -> (u16, Vec<u8>) {
+        let boundary = "gshare3-test-boundary";
+        let mut body = String::new();
+        for (filename, contents) in files {
+            body += &format!("--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\r\n{contents}\r\n");
+        }
+        body += &format!("--{boundary}--\r\n");
+        self.request("POST", path, &format!("Content-Type: multipart/form-data; boundary={boundary}\r\n"), body.as_bytes())
+    }
+
+    pub fn get(&self, path: &str) -> (u16, Vec<u8>) {
+        self.request("GET", path, "", &[])
+    }
+
+    pub fn get_json(&self, path: &str) -> Value {
+        let (status, body) = self.get(path);
+        assert_eq!(status, 200, "{path} returned {status}");
+        serde_json::from_slice(&body).unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Runs a client command in `directory`, returning its output or failing the test with its errors
+pub fn client(directory: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_gshare3")).current_dir(directory).args(args).output().unwrap();
+    assert!(output.status.success(), "{args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut dechunked = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|i| i == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..line_end]).unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return dechunked
+        }
+        dechunked.extend_from_slice(&body[line_end + 2..line_end + 2 + size]);
+        body = &body[line_end + 2 + size + 2..];
+    }
+}
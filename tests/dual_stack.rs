@@ -0,0 +1,44 @@
+//! Checks that clients connecting over IPv4 to a dual-stack socket, which shows them as IPv4-mapped IPv6 addresses, are
+//! authorized the same way as they are with an IPv4 socket
+
+mod common;
+
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, UdpSocket}};
+use common::{Server, NO_FILES};
+
+/// Whitelists an address and a mapped one, and binds to the unspecified IPv6 address, which takes IPv4 connections too
+const ARGS: [&str; 4] = ["10.0.0.5", "::ffff:10.0.0.6", "--bind", "::"];
+
+fn status(server: &Server, ip: IpAddr, headers: &str) -> u16 {
+    server.request_to(ip, "GET", "/", headers, &[]).0
+}
+
+#[test]
+fn loopback_and_local_addresses_are_let_in() {
+    let server = Server::shared("dual-stack-local", &ARGS, NO_FILES);
+    // this comes in as ::ffff:127.0.0.1
+    assert_eq!(status(&server, IpAddr::V4(Ipv4Addr::LOCALHOST), ""), 200);
+    if TcpStream::connect((Ipv6Addr::LOCALHOST, server.port)).is_ok() {
+        assert_eq!(status(&server, IpAddr::V6(Ipv6Addr::LOCALHOST), ""), 200);
+    }
+    // the address of the interface with the default route, connecting to it from here comes from it too. Connecting a UDP socket
+    // doesn't send anything, and fails if there's no such interface
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    if socket.connect("192.0.2.1:9").is_ok() {
+        let local_ip = socket.local_addr().unwrap().ip();
+        assert_eq!(status(&server, local_ip, ""), 200, "{local_ip}");
+    }
+}
+
+#[test]
+fn mapped_addresses_match_ipv4_whitelists() {
+    // the proxy's IPv4 address only matches the connection if that's made canonical too
+    let server = Server::shared("dual-stack-whitelist", &[&ARGS[..], &["--trusted-proxy", "127.0.0.1", "--forwarded-header", "xff"]].concat(), NO_FILES);
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    assert_eq!(status(&server, localhost, "X-Forwarded-For: 10.0.0.5\r\n"), 200);
+    assert_eq!(status(&server, localhost, "X-Forwarded-For: ::ffff:10.0.0.5\r\n"), 200);
+    // whitelisted as a mapped address
+    assert_eq!(status(&server, localhost, "X-Forwarded-For: 10.0.0.6\r\n"), 200);
+    assert_eq!(status(&server, localhost, "X-Forwarded-For: ::ffff:9.9.9.9\r\n"), 401);
+    assert_eq!(status(&server, localhost, "X-Forwarded-For: 9.9.9.9\r\n"), 401);
+}
@@ -0,0 +1,39 @@
+//! Checks that clients that stop reading streamed responses don't keep the `--fs-jobs` permits from everyone else
+
+mod common;
+
+use std::{fs, io::Write, net::TcpStream};
+use common::Server;
+
+const FILES: usize = 20_000;
+const FILESYSTEM_JOBS: usize = 2;
+
+#[test]
+fn stalled_streams_dont_block_other_requests() {
+    let server = Server::start("stalled", &["shared", "--private", "--fs-jobs", &FILESYSTEM_JOBS.to_string()], |root| {
+        let huge = root.join("shared").join("huge");
+        fs::create_dir_all(&huge).unwrap();
+        for i in 0..FILES {
+            // long names, so the listing is more than the socket buffers hold and the server has to wait for the client
+            fs::write(huge.join(format!("{i:0>200}.txt")), "").unwrap();
+        }
+        fs::write(root.join("shared").join("small.txt"), "small").unwrap();
+    });
+    // more of them than there are permits, and none of them ever read a byte of the response
+    let stalled = ["/huge/?data=ndjson", "/huge/?search=txt", "/huge/?data=ndjson", "/huge/?search=0"].map(|path| {
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        stream
+    });
+    assert_eq!(server.get("/small.txt"), (200, b"small".to_vec()));
+    assert_eq!(server.get("/?data=true").0, 200);
+    drop(stalled);
+}
+
+#[test]
+fn zero_jobs_is_rejected() {
+    // there'd be no permits at all, so every request would wait forever
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_gshare3")).args(["--fs-jobs", "0", "--no-mdns"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--fs-jobs"), "{}", String::from_utf8_lossy(&output.stderr));
+}
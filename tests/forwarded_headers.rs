@@ -0,0 +1,51 @@
+//! Checks that clients behind a trusted proxy are whitelisted by the address the proxy saw, and that whatever they put in the
+//! forwarding headers themselves can't get them in
+
+mod common;
+
+use common::{Server, NO_FILES};
+
+/// The only address whitelisted, besides the server's own. The servers are behind a "proxy" on 127.0.0.1, which is the test
+/// itself adding headers the way the proxy would
+const WHITELISTED: &str = "10.0.0.5";
+
+fn status(server: &Server, headers: &str) -> u16 {
+    server.request("GET", "/", headers, &[]).0
+}
+
+#[test]
+fn the_address_the_proxy_adds_is_used() {
+    let server = Server::shared("forwarded-xff", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=xff"], NO_FILES);
+    assert_eq!(status(&server, "X-Forwarded-For: 10.0.0.5\r\n"), 200);
+    assert_eq!(status(&server, "X-Forwarded-For: 9.9.9.9\r\n"), 401);
+    // without the header there's no telling who the client is
+    assert_eq!(status(&server, ""), 401);
+
+    let server = Server::shared("forwarded-forwarded", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=forwarded"], NO_FILES);
+    assert_eq!(status(&server, "Forwarded: for=\"10.0.0.5:1234\";proto=http\r\n"), 200);
+    assert_eq!(status(&server, "Forwarded: for=9.9.9.9\r\n"), 401);
+
+    let server = Server::shared("forwarded-real-ip", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=real-ip"], NO_FILES);
+    assert_eq!(status(&server, "X-Real-IP: 10.0.0.5\r\n"), 200);
+    assert_eq!(status(&server, "X-Real-IP: 9.9.9.9\r\n"), 401);
+}
+
+#[test]
+fn spoofed_headers_are_ignored() {
+    // the proxy only adds to X-Forwarded-For, so the client's own Forwarded and X-Real-IP headers come through as they sent them
+    let server = Server::shared("spoofed-xff", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=xff"], NO_FILES);
+    assert_eq!(status(&server, "Forwarded: for=127.0.0.1\r\nX-Forwarded-For: 9.9.9.9\r\n"), 401);
+    assert_eq!(status(&server, "X-Real-IP: 10.0.0.5\r\nX-Forwarded-For: 9.9.9.9\r\n"), 401);
+    // and whatever the client put in X-Forwarded-For comes before the address the proxy added
+    assert_eq!(status(&server, "X-Forwarded-For: 127.0.0.1, 9.9.9.9\r\n"), 401);
+    assert_eq!(status(&server, "X-Forwarded-For: 10.0.0.5, 9.9.9.9\r\n"), 401);
+    assert_eq!(status(&server, "X-Forwarded-For: garbage, 9.9.9.9\r\n"), 401);
+
+    let server = Server::shared("spoofed-forwarded", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=forwarded"], NO_FILES);
+    assert_eq!(status(&server, "X-Forwarded-For: 127.0.0.1\r\nForwarded: for=9.9.9.9\r\n"), 401);
+    assert_eq!(status(&server, "Forwarded: for=10.0.0.5, for=9.9.9.9\r\n"), 401);
+
+    // a second X-Real-IP means the client sent one the proxy didn't replace
+    let server = Server::shared("spoofed-real-ip", &[WHITELISTED, "--bind=127.0.0.1", "--trusted-proxy=127.0.0.1", "--forwarded-header=real-ip"], NO_FILES);
+    assert_eq!(status(&server, "X-Real-IP: 10.0.0.5\r\nX-Real-IP: 9.9.9.9\r\n"), 401);
+}
@@ -0,0 +1,177 @@
+//! Serves files with names that need escaping in urls, or aren't valid UTF-8 at all, and checks every url the server
+//! gives out for them leads back to the right file
+
+mod common;
+
+use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+use common::Server;
+use serde_json::Value;
+
+/// Names (as bytes, since some of them aren't UTF-8) and their urls as the server should make them
+const FILES: [(&[u8], &str); 18] = [
+    (b"space name.txt", "/space%20name.txt"),
+    (b"hash#tag.txt", "/hash%23tag.txt"),
+    (b"question?.txt", "/question%3F.txt"),
+    (b"percent%20literal.txt", "/percent%2520literal.txt"),
+    (b"plus+sign.txt", "/plus%2Bsign.txt"),
+    (b"colon:first.txt", "/colon%3Afirst.txt"),
+    (b"ampersand&equals=.txt", "/ampersand%26equals%3D.txt"),
+    (b"quotes'\"<>.txt", "/quotes%27%22%3C%3E.txt"),
+    (b"back\\slash.txt", "/back%5Cslash.txt"),
+    (b"new\nline.txt", "/new%0Aline.txt"),
+    (b"~tilde-dash_under.score", "/~tilde-dash_under.score"),
+    ("ünïcödé.txt".as_bytes(), "/%C3%BCn%C3%AFc%C3%B6d%C3%A9.txt"),
+    ("日本語.txt".as_bytes(), "/%E6%97%A5%E6%9C%AC%E8%AA%9E.txt"),
+    ("emoji 🎉.txt".as_bytes(), "/emoji%20%F0%9F%8E%89.txt"),
+    // Latin-1, which isn't valid UTF-8
+    (b"latin1 \xe9.txt", "/latin1%20%E9.txt"),
+    (b"lone \xff byte", "/lone%20%FF%20byte"),
+    (b"  ", "/%20%20"),
+    (b"..dots", "/..dots"),
+];
+
+/// Every file in FILES (each containing its index), a directory with an awkward name, and a file next to the served
+/// directory that nothing should reach
+fn files() -> Vec<(&'static OsStr, String)> {
+    let mut files = FILES.iter().enumerate().map(|(index, (name, _))| (OsStr::from_bytes(name), index.to_string())).collect::<Vec<_>>();
+    files.push((OsStr::new("dir #1/inner%.txt"), String::from("inner")));
+    files.push((OsStr::new("../secret.txt"), String::from("secret")));
+    files
+}
+
+fn entry_urls(listing: &Value) -> Vec<String> {
+    listing["entries"].as_array().unwrap().iter().map(|i| i["url"].as_str().unwrap().to_string()).collect()
+}
+
+#[test]
+fn listing_urls_lead_to_the_files() {
+    let server = Server::shared("listing", &["--private"], &files());
+    let urls = entry_urls(&server.get_json("/?data=true"));
+    for (index, (name, url)) in FILES.iter().enumerate() {
+        assert!(urls.contains(&url.to_string()), "{} should be listed as {url}, got {urls:?}", String::from_utf8_lossy(name));
+        assert_eq!(server.get(url), (200, index.to_string().into_bytes()), "{url}");
+    }
+    assert!(urls.contains(&String::from("/dir%20%231/")), "{urls:?}");
+    assert_eq!(entry_urls(&server.get_json("/dir%20%231/?data=true")), ["/dir%20%231/inner%25.txt"]);
+    assert_eq!(server.get("/dir%20%231/inner%25.txt"), (200, b"inner".to_vec()));
+}
+
+#[test]
+fn names_that_arent_utf8_are_shown_with_replacement_characters() {
+    let server = Server::shared("lossy", &["--private"], &files());
+    let listing = server.get_json("/?data=true");
+    let entry = listing["entries"].as_array().unwrap().iter().find(|i| i["url"] == "/latin1%20%E9.txt").unwrap();
+    assert_eq!(entry["name"], "latin1 \u{fffd}.txt");
+    let (status, page) = server.get("/latin1%20%E9.txt?view=1");
+    assert_eq!(status, 200);
+    assert!(String::from_utf8_lossy(&page).contains("latin1 \u{fffd}.txt"));
+}
+
+#[test]
+fn pages_work_for_every_name() {
+    let server = Server::shared("pages", &["--private"], &files());
+    for (_, url) in FILES {
+        assert_eq!(server.get(&format!("{url}?view=1")).0, 200, "{url}");
+        assert_eq!(server.get(&format!("{url}?data=true")).0, 200, "{url}");
+    }
+    assert_eq!(server.get("/dir%20%231/").0, 200);
+    // the breadcrumbs link to the directory the way the listing does
+    let listing = server.get_json("/dir%20%231/?data=true");
+    assert_eq!(listing["path_components"][1]["name"], "dir #1");
+    assert_eq!(listing["path_components"][1]["url"], "/dir%20%231/");
+}
+
+#[test]
+fn escaping_more_than_needed_is_the_same_path() {
+    let server = Server::shared("normalize", &["--private"], &files());
+    for url in ["/%7Etilde-dash_under.score", "/%7etilde-dash_under%2Escore", "/%c3%bcn%c3%afc%c3%b6d%c3%a9.txt", "/space%20name%2etxt"] {
+        assert_eq!(server.get(url).0, 200, "{url}");
+    }
+    // the urls made from the request path are the usual ones however it was escaped
+    let metadata = server.get_json("/%7etilde-dash_under%2Escore?data=true");
+    assert_eq!(metadata["url"], "/~tilde-dash_under.score");
+}
+
+#[test]
+fn escaped_slashes_and_dot_dot_stay_inside() {
+    let server = Server::shared("traversal", &["--private"], &files());
+    for url in ["/..%2Fsecret.txt", "/%2E%2E/secret.txt", "/%2e%2e%2fsecret.txt", "/dir%20%231%2F..%2F..%2Fsecret.txt", "/dir%20%231/%2E%2E/%2E%2E/secret.txt", "/%00"] {
+        assert_eq!(server.get(url).0, 404, "{url}");
+    }
+    assert_eq!(server.request("GET", "/api/v1/stat/..%2Fsecret.txt", "", &[]).0, 404);
+}
+
+#[test]
+fn search_and_manifest_urls_lead_to_the_files() {
+    let server = Server::shared("search", &["--private"], &files());
+    let (status, results) = server.get("/?search=.txt");
+    assert_eq!(status, 200);
+    let urls = String::from_utf8(results).unwrap().lines().map(|i| serde_json::from_str::<Value>(i).unwrap()["url"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+    assert!(urls.contains(&String::from("/latin1%20%E9.txt")), "{urls:?}");
+    assert!(urls.contains(&String::from("/dir%20%231/inner%25.txt")), "{urls:?}");
+    for url in urls {
+        assert_eq!(server.get(&url).0, 200, "{url}");
+    }
+    let manifest = server.get_json("/?manifest=true");
+    for file in manifest.as_array().unwrap() {
+        let url = file["url"].as_str().unwrap();
+        assert_eq!(server.get(url).0, 200, "{url}");
+    }
+}
+
+#[test]
+fn uploads_keep_their_names_and_stay_in_the_directory() {
+    let server = Server::shared("upload", &["--private", "--upload"], &files());
+    let (status, response) = server.upload("/dir%20%231/", &[("../escaped.txt", "escaped"), ("hash#question?.txt", "hash"), ("/absolute.txt", "absolute"), ("..", "dots")]);
+    assert_eq!(status, 201);
+    assert_eq!(serde_json::from_slice::<Vec<String>>(&response).unwrap(), ["escaped.txt", "hash#question?.txt", "absolute.txt"]);
+    assert!(!server.root.join("shared").join("escaped.txt").exists());
+    assert_eq!(server.get("/dir%20%231/escaped.txt"), (200, b"escaped".to_vec()));
+    assert_eq!(server.get("/dir%20%231/hash%23question%3F.txt"), (200, b"hash".to_vec()));
+    assert_eq!(server.get("/dir%20%231/absolute.txt"), (200, b"absolute".to_vec()));
+}
+
+#[test]
+fn failed_uploads_are_errors() {
+    let server = Server::shared("upload-failed", &["--private", "--upload-overwrite"], &files());
+    // a directory is already there with the name, so there's nothing to overwrite
+    let files = [("dir #1", "not a directory")];
+    assert_eq!(server.upload("/", &files).0, 500);
+    let (status, response) = server.upload("/api/v1/upload/", &files);
+    assert_eq!(status, 500);
+    assert_eq!(serde_json::from_slice::<Value>(&response).unwrap()["error"], "io");
+    assert_eq!(server.get("/dir%20%231/inner%25.txt"), (200, b"inner".to_vec()));
+}
+
+#[test]
+fn failed_overwrites_keep_the_original_file() {
+    let server = Server::shared("upload-read-only", &["--private", "--upload-overwrite"], &files());
+    let path = server.root.join("shared").join("space name.txt");
+    let mut permissions = fs::metadata(&path).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions).unwrap();
+    // root can write to read-only files anyway, in which case the upload just succeeds
+    let can_write = fs::OpenOptions::new().write(true).open(&path).is_ok();
+
+    let (status, _) = server.upload("/", &[("space name.txt", "overwritten")]);
+    assert!(path.exists());
+    if !can_write {
+        assert_eq!(status, 500);
+        assert_eq!(fs::read(&path).unwrap(), b"0");
+    }
+}
+
+#[test]
+fn files_from_before_1970_are_listed() {
+    let server = Server::shared("before-1970", &["--private"], &files());
+    let path = server.root.join("shared").join("dir #1").join("inner%.txt");
+    fs::File::options().write(true).open(&path).unwrap().set_modified(std::time::UNIX_EPOCH - std::time::Duration::from_secs(86_400)).unwrap();
+    let listing = server.get_json("/dir%20%231/?data=true&sort=modified");
+    assert_eq!(listing["entries"][0]["modified"], 0.0);
+    let (status, lines) = server.get("/dir%20%231/?data=ndjson");
+    assert_eq!(status, 200);
+    assert!(String::from_utf8(lines).unwrap().contains("inner%.txt"));
+    let (status, results) = server.get("/?search=inner");
+    assert_eq!(status, 200);
+    assert!(String::from_utf8(results).unwrap().contains("inner%.txt"));
+}
@@ -0,0 +1,32 @@
+//! Checks how the query parameters of the website's urls are read
+
+mod common;
+
+use common::{Server, NO_FILES};
+
+#[test]
+fn flags_can_be_written_without_true() {
+    let server = Server::shared("flags", &["--private"], &[("a.txt", "a")]);
+    for url in ["/?usage", "/?usage=1", "/?usage=true"] {
+        assert!(server.get_json(url)["children"].is_array(), "{url}");
+    }
+    assert_eq!(server.get("/?usage=0").0, 200);
+    assert_eq!(server.get("/?data=true&usage=0").1, server.get("/?data=true").1);
+}
+
+#[test]
+fn invalid_parameters_are_rejected() {
+    let server = Server::shared("invalid", &["--private"], &[("a.txt", "a")]);
+    for url in ["/?data=true&limit=ten", "/?data=true&sort=color", "/?depth=-1&usage", "/a.txt?thumb=big"] {
+        assert_eq!(server.get(url).0, 400, "{url}");
+    }
+}
+
+#[test]
+fn api_flags_can_be_written_without_true() {
+    let server = Server::shared("api-flags", &["--private", "--upload"], NO_FILES);
+    for (index, flag) in ["create_directories", "create_directories=1", "create_directories=true"].into_iter().enumerate() {
+        assert_eq!(server.upload(&format!("/api/v1/upload/new{index}/?{flag}"), &[("a.txt", "a")]).0, 201, "{flag}");
+    }
+    assert_eq!(server.get("/new0/a.txt"), (200, b"a".to_vec()));
+}
@@ -0,0 +1,56 @@
+//! Syncs a local directory with a server both ways, and checks that files changed on both sides end up on both in both versions
+
+mod common;
+
+use std::{fs, process::Command};
+use common::{client, Server};
+
+const FILES: &[(&str, &str)] = &[("docs/notes.txt", "original"), ("remote.txt", "remote")];
+
+fn sync(server: &Server) -> String {
+    client(&server.root, &["sync", "local", &server.url("/")])
+}
+
+#[test]
+fn conflicts_keep_both_versions() {
+    let server = Server::shared("sync-conflict", &["--private", "--upload-overwrite"], FILES);
+    let (local, shared) = (server.root.join("local"), server.root.join("shared"));
+    fs::create_dir_all(&local).unwrap();
+    fs::write(local.join("local.txt"), "local").unwrap();
+    sync(&server);
+    assert_eq!(fs::read_to_string(local.join("docs").join("notes.txt")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(local.join("remote.txt")).unwrap(), "remote");
+    assert_eq!(fs::read_to_string(shared.join("local.txt")).unwrap(), "local");
+
+    // only changed here, changed only on the server, and changed on both
+    fs::write(local.join("local.txt"), "local, changed").unwrap();
+    fs::write(shared.join("remote.txt"), "remote, changed").unwrap();
+    fs::write(local.join("docs").join("notes.txt"), "changed here").unwrap();
+    fs::write(shared.join("docs").join("notes.txt"), "changed on the server").unwrap();
+    let output = sync(&server);
+    assert!(output.contains("1\u{1b}[0m conflicts"), "{output}");
+    assert_eq!(fs::read_to_string(shared.join("local.txt")).unwrap(), "local, changed");
+    assert_eq!(fs::read_to_string(local.join("remote.txt")).unwrap(), "remote, changed");
+    for directory in [&local, &shared] {
+        assert_eq!(fs::read_to_string(directory.join("docs").join("notes.txt")).unwrap(), "changed here");
+        assert_eq!(fs::read_to_string(directory.join("docs").join("notes (1).txt")).unwrap(), "changed on the server");
+    }
+
+    // and after that everything's the same on both sides
+    let output = sync(&server);
+    assert!(output.contains("\u{1b}[96m0\u{1b}[0m conflicts, \u{1b}[96m4\u{1b}[0m already up to date"), "{output}");
+}
+
+#[test]
+fn servers_that_rename_uploads_are_found_out_first() {
+    let server = Server::shared("sync-rename", &["--private", "--upload"], FILES);
+    let local = server.root.join("local");
+    fs::create_dir_all(&local).unwrap();
+    fs::write(local.join("docs").with_extension("txt"), "local").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_gshare3")).current_dir(&server.root).args(["sync", "local", &server.url("/")]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--upload-overwrite"), "{}", String::from_utf8_lossy(&output.stderr));
+    // nothing was downloaded before it failed
+    assert!(!local.join("remote.txt").exists());
+    assert!(!server.root.join("shared").join("docs.txt").exists());
+}